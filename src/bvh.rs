@@ -0,0 +1,383 @@
+//! Bounding volume hierarchies built on the CPU with binned SAH and flattened into the node array the path tracer walks.
+//! The same builder makes the bottom level over a mesh's triangles and the top level over instances.
+
+use vulkano::buffer::BufferContents;
+
+use crate::math::{Mat4, Vec3};
+use crate::scene::Triangle;
+
+
+
+
+/// Bins per axis when looking for a split, more gives slightly better trees but slower builds
+const BINS: usize = 16;
+
+/// Deeper nodes are forced to be leaves, so the shader's traversal stack can't overflow
+pub(crate) const MAX_DEPTH: usize = 63;
+
+/// Leaves bigger than this are split even when SAH thinks it isn't worth it
+const MAX_LEAF_SIZE: usize = 16;
+
+// cost of one ray-box test relative to one ray-triangle test
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+
+
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+
+impl Aabb {
+    /// Contains nothing, growing it by anything gives that thing's bounds
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY)
+    };
+
+    pub fn triangle(triangle: &Triangle) -> Self {
+        let mut bounds = Aabb::EMPTY;
+        for vertex in triangle.vertices {
+            bounds.grow(vertex);
+        }
+        return bounds;
+    }
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        return Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max)
+        };
+    }
+
+    /// Bounds of the transformed corners, which contain the transformed box
+    pub fn transform(self, transform: &Mat4) -> Aabb {
+        let mut bounds = Aabb::EMPTY;
+
+        for corner in 0..8 {
+            let x = if corner & 1 == 0 { self.min.x } else { self.max.x };
+            let y = if corner & 2 == 0 { self.min.y } else { self.max.y };
+            let z = if corner & 4 == 0 { self.min.z } else { self.max.z };
+            bounds.grow(transform.transform_point(Vec3::new(x, y, z)));
+        }
+
+        return bounds;
+    }
+
+    pub fn center(self) -> Vec3 {
+        return (self.min + self.max) * 0.5;
+    }
+
+    /// Zero for empty boxes
+    pub fn surface_area(self) -> f32 {
+        let size = self.max - self.min;
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            return 0.0;
+        }
+
+        return 2.0 * (size.x * size.y + size.y * size.z + size.z * size.x);
+    }
+}
+
+
+
+
+// must match the BvhNode struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy, Debug)]
+#[repr(C)]
+pub(crate) struct BvhNode {
+    pub min: [f32; 3],
+    /// The first primitive of a leaf, or the left child of an interior node. The right child always follows the left one
+    pub left_first: u32,
+    pub max: [f32; 3],
+    /// Primitives in a leaf, zero for interior nodes
+    pub count: u32,
+}
+
+
+pub(crate) struct Bvh {
+    /// The root is the first node
+    pub nodes: Vec<BvhNode>,
+    /// Leaves refer to primitives in this order, so the primitives have to be uploaded sorted by it
+    pub order: Vec<u32>,
+}
+
+
+impl Bvh {
+    /// Builds over anything with bounds, usually triangles or instances
+    pub fn build(bounds: Vec<Aabb>) -> Self {
+        let count = bounds.len();
+
+        let mut builder = Builder {
+            centroids: bounds.iter().map(|bounds| bounds.center()).collect(),
+            bounds: bounds,
+            order: (0..count as u32).collect(),
+            nodes: Vec::with_capacity((2 * count).saturating_sub(1)),
+        };
+
+        builder.nodes.push(BvhNode { min: [0.0; 3], left_first: 0, max: [0.0; 3], count: 0 });
+        builder.subdivide(0, 0, count, 0);
+
+        return Bvh {
+            nodes: builder.nodes,
+            order: builder.order
+        };
+    }
+
+
+    pub fn over_triangles(triangles: &[Triangle]) -> Self {
+        return Self::build(triangles.iter().map(Aabb::triangle).collect());
+    }
+
+
+    pub fn bounds(&self) -> Aabb {
+        let root = &self.nodes[0];
+        return Aabb { min: Vec3::from(root.min), max: Vec3::from(root.max) };
+    }
+}
+
+
+
+
+/////////// Building
+
+struct Builder {
+    bounds: Vec<Aabb>,
+    centroids: Vec<Vec3>,
+    order: Vec<u32>,
+    nodes: Vec<BvhNode>,
+}
+
+
+struct Split {
+    axis: usize,
+    /// Triangles in bins below this one go left
+    bin: usize,
+    cost: f32,
+}
+
+
+impl Builder {
+    fn subdivide(&mut self, node: usize, first: usize, count: usize, depth: usize) {
+        let primitives = &self.order[first..first + count];
+
+        let bounds = primitives.iter().fold(Aabb::EMPTY, |bounds, &primitive| bounds.union(self.bounds[primitive as usize]));
+        let centroid_bounds = primitives.iter().fold(Aabb::EMPTY, |mut bounds, &primitive| {
+            bounds.grow(self.centroids[primitive as usize]);
+            return bounds;
+        });
+
+        self.nodes[node] = BvhNode {
+            min: bounds.min.into(),
+            left_first: first as u32,
+            max: bounds.max.into(),
+            count: count as u32
+        };
+
+        if count <= 1 || depth >= MAX_DEPTH {
+            return;
+        }
+
+        // every centroid in the same spot, there is nothing to split
+        let Some(split) = self.find_split(first, count, centroid_bounds) else {
+            return;
+        };
+
+        let leaf_cost = count as f32 * INTERSECTION_COST;
+        let split_cost = TRAVERSAL_COST + split.cost / bounds.surface_area() * INTERSECTION_COST;
+
+        // a NaN cost from a flat node isn't worth splitting either
+        let worth_splitting = split_cost < leaf_cost;
+        if !worth_splitting && count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        // partition the primitives of this node in place around the split
+        let mut left_end = first;
+        let mut right_start = first + count;
+
+        while left_end < right_start {
+            let centroid = self.centroids[self.order[left_end] as usize];
+            if bin_of(centroid, centroid_bounds, split.axis) < split.bin {
+                left_end += 1;
+            } else {
+                right_start -= 1;
+                self.order.swap(left_end, right_start);
+            }
+        }
+
+        let left_count = left_end - first;
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(self.nodes[node]);
+        self.nodes.push(self.nodes[node]);
+
+        self.nodes[node].left_first = left as u32;
+        self.nodes[node].count = 0;
+
+        self.subdivide(left, first, left_count, depth + 1);
+        self.subdivide(left + 1, left_end, count - left_count, depth + 1);
+    }
+
+
+    /// The cheapest split between bins over all three axes, its cost is the SAH sum of area times triangles
+    /// on both sides, not yet divided by the parent's area
+    fn find_split(&self, first: usize, count: usize, centroid_bounds: Aabb) -> Option<Split> {
+        let mut best: Option<Split> = None;
+
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+                continue;
+            }
+
+            let mut bin_bounds = [Aabb::EMPTY; BINS];
+            let mut bin_counts = [0usize; BINS];
+
+            for &primitive in &self.order[first..first + count] {
+                let bin = bin_of(self.centroids[primitive as usize], centroid_bounds, axis);
+                bin_bounds[bin] = bin_bounds[bin].union(self.bounds[primitive as usize]);
+                bin_counts[bin] += 1;
+            }
+
+            // sweep from the right, so right_costs[i] covers bins i and up
+            let mut right_costs = [0.0; BINS];
+            let mut right_bounds = Aabb::EMPTY;
+            let mut right_count = 0;
+
+            for bin in (1..BINS).rev() {
+                right_bounds = right_bounds.union(bin_bounds[bin]);
+                right_count += bin_counts[bin];
+                right_costs[bin] = right_bounds.surface_area() * right_count as f32;
+            }
+
+            // then from the left, checking every split on the way
+            let mut left_bounds = Aabb::EMPTY;
+            let mut left_count = 0;
+
+            for bin in 1..BINS {
+                left_bounds = left_bounds.union(bin_bounds[bin - 1]);
+                left_count += bin_counts[bin - 1];
+
+                let cost = left_bounds.surface_area() * left_count as f32 + right_costs[bin];
+                if best.as_ref().is_none_or(|best| cost < best.cost) {
+                    best = Some(Split { axis: axis, bin: bin, cost: cost });
+                }
+            }
+        }
+
+        return best;
+    }
+}
+
+
+fn bin_of(centroid: Vec3, centroid_bounds: Aabb, axis: usize) -> usize {
+    let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+    let bin = ((centroid[axis] - centroid_bounds.min[axis]) / extent * BINS as f32) as usize;
+    return bin.min(BINS - 1);
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn node_bounds(node: &BvhNode) -> Aabb {
+        return Aabb { min: Vec3::from(node.min), max: Vec3::from(node.max) };
+    }
+
+
+    fn contains(outer: Aabb, inner: Aabb) -> bool {
+        return (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis]);
+    }
+
+
+    /// Checks the tree below node and returns how deep it goes, counting how often every primitive is in a leaf
+    fn check(bvh: &Bvh, bounds: &[Aabb], node: usize, depth: usize, seen: &mut [u32]) -> usize {
+        let parent = &bvh.nodes[node];
+
+        if parent.count > 0 {
+            let first = parent.left_first as usize;
+            for &primitive in &bvh.order[first..first + parent.count as usize] {
+                assert!(contains(node_bounds(parent), bounds[primitive as usize]), "leaf {node} doesn't contain primitive {primitive}");
+                seen[primitive as usize] += 1;
+            }
+            return depth;
+        }
+
+        let left = parent.left_first as usize;
+        for child in [left, left + 1] {
+            assert!(contains(node_bounds(parent), node_bounds(&bvh.nodes[child])), "node {node} doesn't contain child {child}");
+        }
+
+        return check(bvh, bounds, left, depth + 1, seen).max(check(bvh, bounds, left + 1, depth + 1, seen));
+    }
+
+
+    /// Returns the depth of the tree
+    fn check_tree(bounds: Vec<Aabb>) -> usize {
+        let bvh = Bvh::build(bounds.clone());
+        let mut seen = vec![0; bounds.len()];
+        let depth = check(&bvh, &bounds, 0, 0, &mut seen);
+
+        assert!(seen.iter().all(|&count| count == 1), "every primitive should be in exactly one leaf");
+        return depth;
+    }
+
+
+    fn cube(center: Vec3, size: f32) -> Aabb {
+        return Aabb { min: center - Vec3::splat(size), max: center + Vec3::splat(size) };
+    }
+
+
+    #[test]
+    fn leaves_hold_every_primitive_once() {
+        // a scrambled grid, so every axis gets split
+        let bounds = (0..1000u32)
+            .map(|i| i.wrapping_mul(7919) % 1000)
+            .map(|i| cube(Vec3::new((i % 10) as f32, (i / 10 % 10) as f32, (i / 100) as f32), 0.3 + (i % 7) as f32 * 0.1))
+            .collect();
+
+        check_tree(bounds);
+    }
+
+
+    #[test]
+    fn single_and_flat_primitives() {
+        check_tree(vec![cube(Vec3::ZERO, 1.0)]);
+
+        // all in one plane, so the parents have no volume
+        check_tree((0..100).map(|i| Aabb { min: Vec3::new(i as f32, 0.0, 0.0), max: Vec3::new(i as f32 + 1.0, 0.0, 1.0) }).collect());
+    }
+
+
+    #[test]
+    fn coincident_centroids_stay_shallow() {
+        let depth = check_tree((0..500).map(|i| cube(Vec3::splat(1.0), 1.0 + i as f32)).collect());
+        assert!(depth <= MAX_DEPTH);
+    }
+
+
+    #[test]
+    fn lopsided_input_stays_within_the_limit() {
+        // every split can only peel a few primitives off the far end
+        let bounds: Vec<Aabb> = (0..120).map(|i| cube(Vec3::new(2.0f32.powi(i), 0.0, 0.0), 0.1)).collect();
+
+        let depth = check_tree(bounds);
+        assert!(depth <= MAX_DEPTH, "depth {depth}");
+    }
+}
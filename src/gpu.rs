@@ -1,26 +1,370 @@
 
 
 
+use std::error::Error;
+use std::fmt;
 use std::sync::Arc;
 
-use vulkano::buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer};
+use vulkano::acceleration_structure::{
+    AccelerationStructure, AccelerationStructureBuildGeometryInfo, AccelerationStructureBuildRangeInfo,
+    AccelerationStructureBuildType, AccelerationStructureCreateInfo, AccelerationStructureGeometries,
+    AccelerationStructureType, BuildAccelerationStructureFlags, BuildAccelerationStructureMode,
+};
+use vulkano::buffer::{AllocateBufferError, Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer};
 use vulkano::command_buffer::allocator::{StandardCommandBufferAllocator, StandardCommandBufferAllocatorCreateInfo};
-use vulkano::command_buffer::PrimaryAutoCommandBuffer;
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, CopyBufferInfo, PrimaryAutoCommandBuffer};
 use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
-use vulkano::device::physical::PhysicalDevice;
-use vulkano::device::{Device, DeviceCreateInfo, Queue, QueueCreateInfo, QueueFlags};
-use vulkano::memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator};
-use vulkano::VulkanLibrary;
+use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
+use vulkano::device::{Device, DeviceCreateInfo, DeviceExtensions, DeviceFeatures, Queue, QueueCreateInfo, QueueFlags};
+use vulkano::image::{AllocateImageError, Image, ImageCreateInfo};
+use vulkano::memory::allocator::{AllocationCreateInfo, DeviceLayout, MemoryAllocatorError, MemoryTypeFilter, StandardMemoryAllocator};
+use vulkano::{LoadingError, Validated, ValidationError, Version, VulkanError, VulkanLibrary};
 use vulkano::instance::{Instance, InstanceCreateFlags, InstanceCreateInfo};
-use vulkano::sync::GpuFuture;
+use vulkano::sync::{GpuFuture, HostAccessError, Sharing};
+
+
+
+
+
+/// Everything that can go wrong while setting up or talking to the GPU
+#[derive(Debug)]
+pub enum GPUError {
+    /// The Vulkan loader (libvulkan.so / vulkan-1.dll) could not be found or loaded
+    MissingLoader(LoadingError),
+    /// Vulkan loaded fine but reported no physical devices
+    NoDevice,
+    /// Devices exist but none of them satisfy the DeviceSelection
+    NoMatchingDevice(String),
+    /// A device index or name that can't be a DeviceSelection
+    InvalidSelection(String),
+    /// None of the device's queue families can do the work we need
+    NoSuitableQueue,
+    /// The device can't do something that was asked for, e.g. hardware ray tracing
+    Unsupported(String),
+    /// Host or device memory ran out
+    OutOfMemory,
+    /// A memory allocation failed for a reason other than running out
+    Allocation(MemoryAllocatorError),
+    /// Submitting or executing a command buffer failed
+    Execution(Box<dyn Error + Send + Sync>),
+    /// A host visible buffer couldn't be mapped for reading or writing
+    HostAccess(HostAccessError),
+    /// vulkano rejected a call before it reached the driver
+    Validation(Box<ValidationError>),
+    /// Any other error returned by the driver
+    Vulkan(VulkanError),
+}
+
+impl fmt::Display for GPUError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            GPUError::MissingLoader(err) => write!(f, "no local Vulkan library/DLL: {err}"),
+            GPUError::NoDevice => write!(f, "no Vulkan devices available"),
+            GPUError::NoMatchingDevice(reason) => write!(f, "no device matches the selection: {reason}"),
+            GPUError::InvalidSelection(reason) => write!(f, "invalid device selection: {reason}"),
+            GPUError::NoSuitableQueue => write!(f, "couldn't find a suitable queue family"),
+            GPUError::Unsupported(reason) => write!(f, "not supported by this device: {reason}"),
+            GPUError::OutOfMemory => write!(f, "out of memory"),
+            GPUError::Allocation(err) => write!(f, "memory allocation failed: {err}"),
+            GPUError::Execution(err) => write!(f, "command buffer execution failed: {err}"),
+            GPUError::HostAccess(err) => write!(f, "couldn't access buffer from the host: {err}"),
+            GPUError::Validation(err) => write!(f, "validation error: {err}"),
+            GPUError::Vulkan(err) => write!(f, "vulkan error: {err}"),
+        };
+    }
+}
+
+impl Error for GPUError {}
+
+impl From<LoadingError> for GPUError {
+    fn from(err: LoadingError) -> Self {
+        return GPUError::MissingLoader(err);
+    }
+}
+
+impl From<VulkanError> for GPUError {
+    fn from(err: VulkanError) -> Self {
+        return match err {
+            VulkanError::OutOfHostMemory | VulkanError::OutOfDeviceMemory => GPUError::OutOfMemory,
+            err => GPUError::Vulkan(err),
+        };
+    }
+}
+
+impl From<AllocateBufferError> for GPUError {
+    fn from(err: AllocateBufferError) -> Self {
+        return match err {
+            AllocateBufferError::CreateBuffer(err) | AllocateBufferError::BindMemory(err) => err.into(),
+            AllocateBufferError::AllocateMemory(MemoryAllocatorError::AllocateDeviceMemory(err)) => err.into(),
+            AllocateBufferError::AllocateMemory(MemoryAllocatorError::OutOfPoolMemory) => GPUError::OutOfMemory,
+            AllocateBufferError::AllocateMemory(err) => GPUError::Allocation(err),
+        };
+    }
+}
+
+impl From<AllocateImageError> for GPUError {
+    fn from(err: AllocateImageError) -> Self {
+        return match err {
+            AllocateImageError::CreateImage(err) | AllocateImageError::BindMemory(err) => err.into(),
+            AllocateImageError::AllocateMemory(MemoryAllocatorError::AllocateDeviceMemory(err)) => err.into(),
+            AllocateImageError::AllocateMemory(MemoryAllocatorError::OutOfPoolMemory) => GPUError::OutOfMemory,
+            AllocateImageError::AllocateMemory(err) => GPUError::Allocation(err),
+        };
+    }
+}
+
+impl From<HostAccessError> for GPUError {
+    fn from(err: HostAccessError) -> Self {
+        return GPUError::HostAccess(err);
+    }
+}
+
+impl From<Box<ValidationError>> for GPUError {
+    fn from(err: Box<ValidationError>) -> Self {
+        return GPUError::Validation(err);
+    }
+}
+
+impl<E> From<Validated<E>> for GPUError where E: Into<GPUError> {
+    fn from(err: Validated<E>) -> Self {
+        return match err {
+            Validated::Error(err) => err.into(),
+            Validated::ValidationError(err) => GPUError::Validation(err),
+        };
+    }
+}
+
+
+
+
+
+/// Environment variable read by DeviceSelection::from_env, holds a device index or part of a device name
+pub const DEVICE_ENV_VAR: &str = "PATHTRACER_DEVICE";
+
+
+/// How GPU::init picks between several physical devices.
+/// Devices that lack the required extensions/features or a usable queue are never picked;
+/// of the rest an explicit index or name wins, otherwise the best device type does
+/// (discrete > integrated > virtual > cpu).
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSelection {
+    /// Position of the device in Vulkan's enumeration order, as printed at startup
+    pub index: Option<usize>,
+    /// Case insensitive substring of the device name, e.g. "nvidia" or "llvmpipe"
+    pub name: Option<String>,
+    pub required_extensions: DeviceExtensions,
+    pub required_features: DeviceFeatures,
+}
 
+impl DeviceSelection {
+    /// Treats numbers as an index and anything else as a name
+    pub fn parse(value: &str) -> Result<Self, GPUError> {
+        let value = value.trim();
 
+        if value.is_empty() {
+            return Err(GPUError::InvalidSelection("the device is empty, give an index or part of a name".to_string()));
+        }
+
+        if let Ok(index) = value.parse::<usize>() {
+            return Ok(Self { index: Some(index), ..Default::default() });
+        }
 
+        // -1 or 0.5 are more likely a mistyped index than part of a name
+        if value.parse::<f64>().is_ok() {
+            return Err(GPUError::InvalidSelection(format!("\"{value}\" isn't a device index, those count up from 0")));
+        }
 
+        return Ok(Self { name: Some(value.to_string()), ..Default::default() });
+    }
+
+    /// Reads PATHTRACER_DEVICE, falling back to the default policy when it is unset or empty
+    pub fn from_env() -> Result<Self, GPUError> {
+        return match std::env::var(DEVICE_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => Self::parse(&value).map_err(|err| match err {
+                GPUError::InvalidSelection(reason) => GPUError::InvalidSelection(format!("{DEVICE_ENV_VAR}: {reason}")),
+                err => err,
+            }),
+            _ => Ok(Self::default()),
+        };
+    }
+
+
+    /// Picks a device and returns it with a human readable reason for the choice
+    fn select(&self, physical_devices: &[Arc<PhysicalDevice>]) -> Result<(Arc<PhysicalDevice>, String), GPUError> {
+        let devices: Vec<DeviceInfo> = physical_devices.iter().enumerate()
+            .map(|(index, physical_device)| {
+                let properties = physical_device.properties();
+                let missing = self.missing(
+                    physical_device.supported_extensions(),
+                    physical_device.supported_features(),
+                    graphics_queue_family(physical_device).is_some()
+                );
+
+                if let Some(missing) = missing {
+                    log::debug!("Skipping device {index} ({}): {missing}", properties.device_name);
+                }
+
+                (index, properties.device_name.as_str(), properties.device_type, missing.is_none())
+            })
+            .collect();
 
+        let (index, reason) = self.choose(&devices)?;
+        return Ok((physical_devices[index].clone(), reason));
+    }
+
+
+    /// What keeps a device from being picked at all, if anything
+    fn missing(&self, extensions: &DeviceExtensions, features: &DeviceFeatures, has_graphics_queue: bool) -> Option<&'static str> {
+        if !extensions.contains(&self.required_extensions) {
+            return Some("missing required extensions");
+        }
+
+        if !features.contains(&self.required_features) {
+            return Some("missing required features");
+        }
+
+        if !has_graphics_queue {
+            return Some("no graphics queue family");
+        }
+
+        return None;
+    }
+
+
+    /// The selection policy on its own, returning the index of the chosen device and the reason for it
+    fn choose(&self, devices: &[DeviceInfo]) -> Result<(usize, String), GPUError> {
+        if devices.is_empty() {
+            return Err(GPUError::NoDevice);
+        }
+
+        let mut candidates = devices.iter().filter(|(_, _, _, supported)| *supported);
+
+        if let Some(wanted) = self.index {
+            return candidates
+                .find(|(index, _, _, _)| *index == wanted)
+                .map(|(index, _, _, _)| (*index, format!("requested index {wanted}")))
+                .ok_or(GPUError::NoMatchingDevice(format!("no usable device at index {wanted}")));
+        }
+
+        if let Some(wanted) = &self.name {
+            let needle = wanted.to_lowercase();
+
+            return candidates
+                .find(|(_, name, _, _)| name.to_lowercase().contains(&needle))
+                .map(|(index, _, _, _)| (*index, format!("name matches \"{wanted}\"")))
+                .ok_or(GPUError::NoMatchingDevice(format!("no usable device named like \"{wanted}\"")));
+        }
+
+        // min_by_key keeps the first of equally ranked devices, so enumeration order breaks ties
+        return candidates
+            .min_by_key(|(_, _, device_type, _)| device_type_rank(*device_type))
+            .map(|(index, _, device_type, _)| (*index, format!("best available device type ({})", device_type_name(*device_type))))
+            .ok_or(GPUError::NoMatchingDevice("no device supports the required extensions, features and queues".to_string()));
+    }
+}
+
+
+/// Index, name, type and whether a device has everything the selection requires
+type DeviceInfo<'a> = (usize, &'a str, PhysicalDeviceType, bool);
+
+
+fn device_type_rank(device_type: PhysicalDeviceType) -> u32 {
+    return match device_type {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 3,
+        _ => 4,
+    };
+}
+
+
+fn device_type_name(device_type: PhysicalDeviceType) -> &'static str {
+    return match device_type {
+        PhysicalDeviceType::DiscreteGpu => "discrete GPU",
+        PhysicalDeviceType::IntegratedGpu => "integrated GPU",
+        PhysicalDeviceType::VirtualGpu => "virtual GPU",
+        PhysicalDeviceType::Cpu => "CPU",
+        _ => "other",
+    };
+}
+
+
+/// Extensions for tracing with ray queries against acceleration structures built by the driver
+fn ray_query_extensions() -> DeviceExtensions {
+    return DeviceExtensions {
+        khr_acceleration_structure: true,
+        khr_deferred_host_operations: true,
+        khr_ray_query: true,
+        ..DeviceExtensions::empty()
+    };
+}
+
+
+fn ray_query_features() -> DeviceFeatures {
+    return DeviceFeatures {
+        acceleration_structure: true,
+        buffer_device_address: true,
+        ray_query: true,
+        ..DeviceFeatures::empty()
+    };
+}
+
+
+/// Buffer device addresses and SPIR-V 1.4 are only core from Vulkan 1.2
+fn supports_ray_query(physical_device: &PhysicalDevice) -> bool {
+    return physical_device.api_version() >= Version::V1_2
+        && physical_device.supported_extensions().contains(&ray_query_extensions())
+        && physical_device.supported_features().contains(&ray_query_features());
+}
+
+
+/// First family with the wanted flags and none of the excluded ones, e.g. an async compute family
+fn dedicated_queue_family(physical_device: &PhysicalDevice, wanted: QueueFlags, excluded: QueueFlags) -> Option<u32> {
+    return physical_device
+        .queue_family_properties()
+        .iter()
+        .position(|queue_family_properties| {
+            queue_family_properties.queue_flags.contains(wanted) && !queue_family_properties.queue_flags.intersects(excluded)
+        })
+        .map(|index| index as u32);
+}
+
+
+fn graphics_queue_family(physical_device: &PhysicalDevice) -> Option<u32> {
+    return physical_device
+        .queue_family_properties()
+        .iter()
+        .position(|queue_family_properties| {
+            queue_family_properties.queue_flags.contains(QueueFlags::GRAPHICS)
+        })
+        .map(|index| index as u32);
+}
+
+
+
+
+
+/// Owns the device plus one queue per kind of work.
+/// Tracing runs on `compute_queue` and uploads/readbacks on `transfer_queue`; when the hardware
+/// has no dedicated families these are simply the same queue as `queue`.
+/// Buffers and images made through GPU are created with concurrent sharing across all of these
+/// families instead of being handed between them with release and acquire barriers: vulkano's
+/// AutoCommandBufferBuilder places its own barriers and can't record ownership transfers, so
+/// exclusive resources would lose their contents on the other family. Concurrent sharing can be
+/// slower on some hardware, which only matters if profiling shows it. Work handed from one queue
+/// to the other is still ordered with a semaphore.
 pub struct GPU {
+    pub physical_device: Arc<PhysicalDevice>,
     pub device: Arc<Device>,
+    /// Graphics queue
     pub queue: Arc<Queue>,
+    pub compute_queue: Arc<Queue>,
+    pub transfer_queue: Arc<Queue>,
+    /// Unique families of the queues above
+    pub queue_family_indices: Vec<u32>,
+    /// Ray queries and acceleration structures are enabled, see supports_ray_query
+    pub ray_query: bool,
     pub memory_allocator: Arc<StandardMemoryAllocator>,
     pub command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     pub descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>
@@ -28,60 +372,93 @@ pub struct GPU {
 
 
 impl GPU {
-    pub fn init() -> Self {
+    /// Initialises with the device selection from PATHTRACER_DEVICE
+    pub fn init() -> Result<Self, GPUError> {
+        return Self::init_with(&DeviceSelection::from_env()?);
+    }
+
+
+    pub fn init_with(selection: &DeviceSelection) -> Result<Self, GPUError> {
         // get vulkan instance
-        let library = VulkanLibrary::new().expect("no local Vulkan library/DLL");
+        let library = VulkanLibrary::new()?;
         let instance = Instance::new(
             library,
             InstanceCreateInfo {
                 flags: InstanceCreateFlags::ENUMERATE_PORTABILITY,
                 ..Default::default()
             },
-        )
-        .expect("failed to create instance");
+        )?;
 
 
         // get devices
-        let physical_devices: Vec<Arc<PhysicalDevice>> = instance.enumerate_physical_devices().expect("could not enumerate devices").collect();
+        let physical_devices: Vec<Arc<PhysicalDevice>> = instance.enumerate_physical_devices()?.collect();
 
-        for physical_device in physical_devices.iter() {
-            println!("Device: {}", physical_device.properties().device_name.clone());
+        for (index, physical_device) in physical_devices.iter().enumerate() {
+            let properties = physical_device.properties();
+            log::info!("Device {index}: {} ({})", properties.device_name, device_type_name(properties.device_type));
         }
 
-        let physical_device = physical_devices.iter().next().expect("no devices available");
+        let (physical_device, reason) = selection.select(&physical_devices)?;
+        log::info!("Using device: {} ({reason})", physical_device.properties().device_name);
 
 
         // get virtual device
         for family in physical_device.queue_family_properties() {
-            println!("Found a queue family with {:?} queue(s). {:?}", family.queue_count, family.queue_flags);
+            log::debug!("Found a queue family with {:?} queue(s). {:?}", family.queue_count, family.queue_flags);
         }
 
-        // pick queue
-        let queue_family_index = physical_device
-            .queue_family_properties()
-            .iter()
-            .enumerate()
-            .position(|(_, queue_family_properties)| {
-                queue_family_properties.queue_flags.contains(QueueFlags::GRAPHICS)
-            })
-            .expect("couldn't find a graphical queue family") as u32;
+        // pick queues, using async compute and DMA transfer families when the hardware has them
+        let graphics_family = graphics_queue_family(&physical_device).ok_or(GPUError::NoSuitableQueue)?;
+        let compute_family = dedicated_queue_family(&physical_device, QueueFlags::COMPUTE, QueueFlags::GRAPHICS)
+            .unwrap_or(graphics_family);
+        let transfer_family = dedicated_queue_family(&physical_device, QueueFlags::TRANSFER, QueueFlags::GRAPHICS | QueueFlags::COMPUTE)
+            .unwrap_or(compute_family);
+
+        log::debug!("Queue families: graphics {graphics_family}, compute {compute_family}, transfer {transfer_family}");
+
+        let mut queue_family_indices = vec![graphics_family, compute_family, transfer_family];
+        queue_family_indices.sort();
+        queue_family_indices.dedup();
+
+        // hardware ray tracing is optional, the renderer falls back to its own BVH without it
+        let ray_query = supports_ray_query(&physical_device);
+        let mut enabled_extensions = selection.required_extensions;
+        let mut enabled_features = selection.required_features;
+
+        if ray_query {
+            enabled_extensions = enabled_extensions.union(&ray_query_extensions());
+            enabled_features = enabled_features.union(&ray_query_features());
+        }
+
+        log::info!("Hardware ray tracing: {}", if ray_query { "supported" } else { "not supported" });
 
         // create device
-        let (device, mut queues) = Device::new(
+        let (device, queues) = Device::new(
             physical_device.clone(),
             DeviceCreateInfo {
-                // here we pass the desired queue family to use by index
-                queue_create_infos: vec![QueueCreateInfo {
+                // one queue from every family we use, roles sharing a family share the queue
+                queue_create_infos: queue_family_indices.iter().map(|&queue_family_index| QueueCreateInfo {
                     queue_family_index,
                     ..Default::default()
-                }],
+                }).collect(),
+                enabled_extensions: enabled_extensions,
+                enabled_features: enabled_features,
                 ..Default::default()
             },
-        )
-        .expect("failed to create device");
+        )?;
+
 
+        let queues: Vec<Arc<Queue>> = queues.collect();
+        let queue_from_family = |family: u32| {
+            return queues.iter()
+                .find(|queue| queue.queue_family_index() == family)
+                .cloned()
+                .ok_or(GPUError::NoSuitableQueue);
+        };
 
-        let queue = queues.next().unwrap();
+        let queue = queue_from_family(graphics_family)?;
+        let compute_queue = queue_from_family(compute_family)?;
+        let transfer_queue = queue_from_family(transfer_family)?;
 
 
 
@@ -102,33 +479,299 @@ impl GPU {
 
 
 
-        return Self {
+        return Ok(Self {
+            physical_device: physical_device,
             device: device,
             queue:queue,
+            compute_queue: compute_queue,
+            transfer_queue: transfer_queue,
+            queue_family_indices: queue_family_indices,
+            ray_query: ray_query,
             memory_allocator: memory_allocator,
             command_buffer_allocator: command_buffer_allocator,
             descriptor_set_allocator: descriptor_set_allocator
-        };
+        });
+    }
+
+
+    /// Concurrent sharing between all our queue families, or exclusive if there is only one
+    fn sharing<I>(&self) -> Sharing<I> where I: FromIterator<u32> + IntoIterator<Item = u32> {
+        if self.queue_family_indices.len() > 1 {
+            return Sharing::Concurrent(self.queue_family_indices.iter().copied().collect());
+        }
+
+        return Sharing::Exclusive;
     }
 
 
-    pub fn buffer_from_iter<I, T>(&self, data: I, usage: BufferUsage, memory_type_filter: MemoryTypeFilter) -> Subbuffer<[T]> where T: BufferContents, I: IntoIterator<Item = T>, I::IntoIter: ExactSizeIterator {
-        return Buffer::from_iter(
+    pub fn buffer_from_iter<I, T>(&self, data: I, usage: BufferUsage, memory_type_filter: MemoryTypeFilter) -> Result<Subbuffer<[T]>, GPUError> where T: BufferContents, I: IntoIterator<Item = T>, I::IntoIter: ExactSizeIterator {
+        let buffer = Buffer::from_iter(
             self.memory_allocator.clone(),
-            BufferCreateInfo { usage: usage, ..Default::default() },
+            BufferCreateInfo { usage: usage, sharing: self.sharing(), ..Default::default() },
             AllocationCreateInfo { memory_type_filter: memory_type_filter, ..Default::default() },
             data
-        ).expect("failed to create buffer");
+        )?;
+
+        return Ok(buffer);
+    }
+
+
+    /// Creates a device local buffer and fills it through a staging buffer on the transfer queue
+    pub fn upload_buffer<I, T>(&self, data: I, usage: BufferUsage) -> Result<Subbuffer<[T]>, GPUError> where T: BufferContents, I: IntoIterator<Item = T>, I::IntoIter: ExactSizeIterator {
+        let staging = self.buffer_from_iter(
+            data,
+            BufferUsage::TRANSFER_SRC,
+            MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+        )?;
+
+        let buffer = Buffer::new_slice::<T>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo { usage: usage | BufferUsage::TRANSFER_DST, sharing: self.sharing(), ..Default::default() },
+            AllocationCreateInfo { memory_type_filter: MemoryTypeFilter::PREFER_DEVICE, ..Default::default() },
+            staging.len()
+        )?;
+
+        let mut builder = self.command_buffer(&self.transfer_queue)?;
+        builder.copy_buffer(CopyBufferInfo::buffers(staging, buffer.clone()))?;
+        self.run_on(&self.transfer_queue, builder.build()?)?;
+
+        return Ok(buffer);
+    }
+
+
+    /// Creates a device local image shared between all our queue families
+    pub fn create_image(&self, create_info: ImageCreateInfo) -> Result<Arc<Image>, GPUError> {
+        let image = Image::new(
+            self.memory_allocator.clone(),
+            ImageCreateInfo { sharing: self.sharing(), ..create_info },
+            AllocationCreateInfo { memory_type_filter: MemoryTypeFilter::PREFER_DEVICE, ..Default::default() }
+        )?;
+
+        return Ok(image);
+    }
+
+
+    /// Builds an acceleration structure on the compute queue and waits for it.
+    /// Only available when `ray_query` is true
+    pub fn build_acceleration_structure(&self, geometries: AccelerationStructureGeometries, primitive_count: u32, ty: AccelerationStructureType) -> Result<Arc<AccelerationStructure>, GPUError> {
+        if !self.ray_query {
+            return Err(GPUError::Unsupported("acceleration structures need VK_KHR_acceleration_structure and VK_KHR_ray_query".to_string()));
+        }
+
+        let mut build_info = AccelerationStructureBuildGeometryInfo {
+            mode: BuildAccelerationStructureMode::Build,
+            flags: BuildAccelerationStructureFlags::PREFER_FAST_TRACE,
+            ..AccelerationStructureBuildGeometryInfo::new(geometries)
+        };
+
+        let sizes = self.device.acceleration_structure_build_sizes(
+            AccelerationStructureBuildType::Device,
+            &build_info,
+            &[primitive_count]
+        )?;
+
+        let storage = Buffer::new_slice::<u8>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::ACCELERATION_STRUCTURE_STORAGE | BufferUsage::SHADER_DEVICE_ADDRESS,
+                sharing: self.sharing(),
+                ..Default::default()
+            },
+            AllocationCreateInfo { memory_type_filter: MemoryTypeFilter::PREFER_DEVICE, ..Default::default() },
+            sizes.acceleration_structure_size
+        )?;
+
+        // the scratch buffer's device address has to be aligned, which new_slice doesn't promise
+        let scratch_alignment = self.physical_device.properties().min_acceleration_structure_scratch_offset_alignment.unwrap_or(1);
+        let scratch_layout = DeviceLayout::from_size_alignment(sizes.build_scratch_size, scratch_alignment as u64).ok_or_else(|| {
+            GPUError::Unsupported(format!(
+                "a {} byte acceleration structure scratch buffer aligned to {scratch_alignment}",
+                sizes.build_scratch_size
+            ))
+        })?;
+
+        let scratch = Subbuffer::new(Buffer::new(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::SHADER_DEVICE_ADDRESS,
+                ..Default::default()
+            },
+            AllocationCreateInfo { memory_type_filter: MemoryTypeFilter::PREFER_DEVICE, ..Default::default() },
+            scratch_layout
+        )?);
+
+        // safe as long as the structure is only read after the build below finished, which run waits for
+        let acceleration_structure = unsafe {
+            AccelerationStructure::new(
+                self.device.clone(),
+                AccelerationStructureCreateInfo { ty: ty, ..AccelerationStructureCreateInfo::new(storage) }
+            )?
+        };
+
+        build_info.dst_acceleration_structure = Some(acceleration_structure.clone());
+        build_info.scratch_data = Some(scratch);
+
+        let range = AccelerationStructureBuildRangeInfo {
+            primitive_count: primitive_count,
+            ..Default::default()
+        };
+
+        let mut builder = self.command_buffer(&self.compute_queue)?;
+        unsafe {
+            builder.build_acceleration_structure(build_info, [range].into_iter().collect())?;
+        }
+
+        self.run(builder.build()?)?;
+        return Ok(acceleration_structure);
     }
 
 
-    pub fn run(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) {
+    /// Starts recording a one time command buffer for the given queue
+    pub fn command_buffer(&self, queue: &Queue) -> Result<AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, GPUError> {
+        let builder = AutoCommandBufferBuilder::primary(
+            self.command_buffer_allocator.clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        return Ok(builder);
+    }
+
+
+    fn run_on(&self, queue: &Arc<Queue>, command_buffer: Arc<PrimaryAutoCommandBuffer>) -> Result<(), GPUError> {
+        let future = vulkano::sync::now(self.device.clone())
+            .then_execute(queue.clone(), command_buffer)
+            .map_err(|err| GPUError::Execution(Box::new(err)))?
+            .then_signal_fence_and_flush()?;
+
+        future.wait(None)?;
+        return Ok(());
+    }
+
+
+    /// Runs a command buffer recorded for the compute queue and waits for it
+    pub fn run(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) -> Result<(), GPUError> {
+        return self.run_on(&self.compute_queue, command_buffer);
+    }
+
+
+    /// Runs a command buffer recorded for the transfer queue and waits for it
+    pub fn run_transfer(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) -> Result<(), GPUError> {
+        return self.run_on(&self.transfer_queue, command_buffer);
+    }
+
+
+    /// Runs `compute` on the compute queue, then `transfer` on the transfer queue once it is done.
+    /// The queues are ordered by a semaphore so the host only waits once, at the end.
+    pub fn run_then_transfer(&self, compute: Arc<PrimaryAutoCommandBuffer>, transfer: Arc<PrimaryAutoCommandBuffer>) -> Result<(), GPUError> {
         let future = vulkano::sync::now(self.device.clone())
-            .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap();
+            .then_execute(self.compute_queue.clone(), compute)
+            .map_err(|err| GPUError::Execution(Box::new(err)))?
+            .then_signal_semaphore()
+            .then_execute(self.transfer_queue.clone(), transfer)
+            .map_err(|err| GPUError::Execution(Box::new(err)))?
+            .then_signal_fence_and_flush()?;
+
+        future.wait(None)?;
+        return Ok(());
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        future.wait(None).unwrap();
+
+    const DEVICES: [DeviceInfo; 5] = [
+        (0, "llvmpipe (LLVM 17.0.6, 256 bits)", PhysicalDeviceType::Cpu, true),
+        (1, "Intel(R) UHD Graphics 630", PhysicalDeviceType::IntegratedGpu, true),
+        (2, "NVIDIA GeForce RTX 3080", PhysicalDeviceType::DiscreteGpu, true),
+        (3, "AMD Radeon RX 6800", PhysicalDeviceType::DiscreteGpu, false),
+        (4, "NVIDIA GeForce RTX 4090", PhysicalDeviceType::DiscreteGpu, true),
+    ];
+
+
+    fn chosen(selection: &DeviceSelection, devices: &[DeviceInfo]) -> Result<usize, GPUError> {
+        return selection.choose(devices).map(|(index, _)| index);
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn best_device_type_wins() {
+        let selection = DeviceSelection::default();
+
+        // the first of equally good devices
+        assert_eq!(chosen(&selection, &DEVICES).unwrap(), 2);
+        assert_eq!(chosen(&selection, &DEVICES[..2]).unwrap(), 1);
+        assert_eq!(chosen(&selection, &DEVICES[..1]).unwrap(), 0);
+
+        // unusable devices are never picked, however good
+        assert_eq!(chosen(&selection, &[DEVICES[1], DEVICES[3]]).unwrap(), 1);
+        assert!(matches!(chosen(&selection, &DEVICES[3..4]), Err(GPUError::NoMatchingDevice(_))));
+        assert!(matches!(chosen(&selection, &[]), Err(GPUError::NoDevice)));
+    }
+
+
+    #[test]
+    fn indices_have_to_be_usable_devices() {
+        assert_eq!(chosen(&DeviceSelection::parse("0").unwrap(), &DEVICES).unwrap(), 0);
+        assert_eq!(chosen(&DeviceSelection::parse("4").unwrap(), &DEVICES).unwrap(), 4);
+        assert!(matches!(chosen(&DeviceSelection::parse("3").unwrap(), &DEVICES), Err(GPUError::NoMatchingDevice(_))));
+        assert!(matches!(chosen(&DeviceSelection::parse("5").unwrap(), &DEVICES), Err(GPUError::NoMatchingDevice(_))));
+    }
+
+
+    #[test]
+    fn names_match_case_insensitive_substrings() {
+        assert_eq!(chosen(&DeviceSelection::parse("geforce").unwrap(), &DEVICES).unwrap(), 2);
+        assert_eq!(chosen(&DeviceSelection::parse(" INTEL ").unwrap(), &DEVICES).unwrap(), 1);
+        assert_eq!(chosen(&DeviceSelection::parse("LLVMpipe").unwrap(), &DEVICES).unwrap(), 0);
+        assert!(matches!(chosen(&DeviceSelection::parse("radeon").unwrap(), &DEVICES), Err(GPUError::NoMatchingDevice(_))));
+        assert!(matches!(chosen(&DeviceSelection::parse("apple").unwrap(), &DEVICES), Err(GPUError::NoMatchingDevice(_))));
+    }
+
+
+    #[test]
+    fn missing_requirements_exclude_a_device() {
+        let selection = DeviceSelection {
+            required_extensions: ray_query_extensions(),
+            required_features: ray_query_features(),
+            ..Default::default()
+        };
+
+        assert_eq!(selection.missing(&DeviceExtensions::empty(), &ray_query_features(), true), Some("missing required extensions"));
+        assert_eq!(selection.missing(&ray_query_extensions(), &DeviceFeatures::empty(), true), Some("missing required features"));
+        assert_eq!(selection.missing(&ray_query_extensions(), &ray_query_features(), false), Some("no graphics queue family"));
+        assert_eq!(selection.missing(&ray_query_extensions(), &ray_query_features(), true), None);
+
+        let more = DeviceExtensions { khr_swapchain: true, ..ray_query_extensions() };
+        assert_eq!(selection.missing(&more, &ray_query_features(), true), None);
+
+        // nothing is required by default
+        assert_eq!(DeviceSelection::default().missing(&DeviceExtensions::empty(), &DeviceFeatures::empty(), true), None);
+    }
+
+
+    #[test]
+    fn parses_indices_and_names() {
+        let selection = DeviceSelection::parse(" 2 ").unwrap();
+        assert_eq!((selection.index, selection.name), (Some(2), None));
+
+        let selection = DeviceSelection::parse("RTX 3080").unwrap();
+        assert_eq!((selection.index, selection.name), (None, Some("RTX 3080".to_string())));
+
+        for value in ["", "  ", "-1", "1.5"] {
+            assert!(matches!(DeviceSelection::parse(value), Err(GPUError::InvalidSelection(_))), "{value:?}");
+        }
+    }
+
+
+    #[test]
+    fn errors_can_cross_threads() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<GPUError>();
+    }
+}
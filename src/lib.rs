@@ -0,0 +1,29 @@
+//! A Vulkan compute shader path tracer.
+//!
+//! ```no_run
+//! use vulkan_pathtracer::{GPU, RenderSettings, Renderer, Scene};
+//!
+//! let renderer = Renderer::new(GPU::init()?)?;
+//! let framebuffer = renderer.render(&Scene::cornell_box(), &RenderSettings::default())?;
+//! framebuffer.into_image().save("image.png")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+// explicit returns and field names are the house style
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+mod bvh;
+pub mod gpu;
+pub mod math;
+pub mod output;
+pub mod renderer;
+pub mod scene;
+mod shaders;
+
+pub use gpu::{DeviceSelection, GPU, GPUError};
+pub use output::{ExrPrecision, OutputError};
+pub use renderer::{
+    Accumulation, Aov, DisplayTransform, Framebuffer, LightSampling, LightSelection, ProgressiveRender, RenderError, RenderSettings, Renderer, ToneMapOperator,
+    TraceBackend,
+};
+pub use scene::{Scene, SceneError};
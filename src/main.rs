@@ -1,232 +1,553 @@
+// explicit returns and field names are the house style
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
 
-use image::{ImageBuffer, Rgba};
-use vulkano::{buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage}, command_buffer::{allocator::{StandardCommandBufferAllocator, StandardCommandBufferAllocatorCreateInfo}, AutoCommandBufferBuilder, CommandBufferUsage, CopyBufferInfo, CopyImageToBufferInfo, RenderPassBeginInfo, SubpassBeginInfo, SubpassContents, SubpassEndInfo}, descriptor_set::{DescriptorSet, WriteDescriptorSet}, format::Format, image::{view::ImageView, Image, ImageCreateInfo, ImageType, ImageUsage}, memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator}, pipeline::{compute::ComputePipelineCreateInfo, graphics::{color_blend::{ColorBlendAttachmentState, ColorBlendState}, input_assembly::InputAssemblyState, multisample::MultisampleState, rasterization::RasterizationState, vertex_input::Vertex, viewport::{Viewport, ViewportState}, GraphicsPipelineCreateInfo}, layout::PipelineDescriptorSetLayoutCreateInfo, ComputePipeline, GraphicsPipeline, PipelineBindPoint, PipelineLayout, PipelineShaderStageCreateInfo}, render_pass::{Framebuffer, FramebufferCreateInfo, Subpass}, sync};
-use vulkano::pipeline::Pipeline;
-use vulkano::pipeline::graphics::vertex_input::VertexDefinition;
-use vulkano::sync::GpuFuture;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-mod gpu;
-mod shaders;
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
+use image::{DynamicImage, ImageFormat};
+use vulkan_pathtracer::output::{self, Layer};
+use vulkan_pathtracer::{
+    Accumulation, Aov, DeviceSelection, ExrPrecision, GPU, LightSampling, LightSelection, OutputError, ProgressiveRender, RenderSettings, Renderer, Scene,
+    ToneMapOperator, TraceBackend,
+};
 
 
 
-#[derive(BufferContents, Vertex)]
-#[repr(C)]
-struct MyVertex {
-    #[format(R32G32_SFLOAT)]
-    position: [f32; 2],
+/// Renders a scene with a Vulkan compute shader path tracer
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Scene file (.toml, .gltf or .glb) to render, the built-in Cornell box is used when omitted
+    #[arg(short, long)]
+    scene: Option<PathBuf>,
+
+    // the render settings below fall back to the scene file's [render] table, then to RenderSettings::default()
+
+    /// Image width in pixels [default: 1024]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=16384))]
+    width: Option<u32>,
+
+    /// Image height in pixels [default: 1024]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=16384))]
+    height: Option<u32>,
+
+    /// Samples per pixel [default: 64]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    spp: Option<u32>,
+
+    /// Samples per pixel in each GPU dispatch, lower it if long dispatches time out [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    spp_per_dispatch: Option<u32>,
+
+    /// Maximum number of bounces after the primary ray [default: 8]
+    #[arg(long)]
+    max_bounces: Option<u32>,
+
+    /// Seed for the per pixel random numbers [default: 0]
+    #[arg(long)]
+    seed: Option<u32>,
+
+    /// How paths find lights. bsdf and light converge to the same image as mis, only slower,
+    /// so they're for checking it [default: mis]
+    #[arg(long, value_enum)]
+    light_sampling: Option<Sampling>,
+
+    /// How a light is picked for light sampling. tree favours lights close to and facing each point,
+    /// which matters with many of them [default: tree]
+    #[arg(long, value_enum)]
+    light_selection: Option<Selection>,
+
+    /// Output image, the format comes from the extension unless --format is given.
+    /// EXR and HDR are linear and keep everything above 1, the others are clamped 8 bit sRGB
+    #[arg(short, long, default_value = "image.png")]
+    output: PathBuf,
+
+    /// Output image format
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Precision of EXR channels, float is better for depth and position
+    #[arg(long, value_enum, default_value = "half")]
+    exr_precision: Precision,
+
+    /// Extra passes to render, comma separated. They become layers of an EXR output,
+    /// or files named like image.depth.exr next to any other format
+    #[arg(long, value_enum, value_delimiter = ',')]
+    aov: Vec<AovName>,
+
+    /// Write the AOVs to their own EXR files even when the output is an EXR
+    #[arg(long)]
+    separate_aovs: bool,
+
+    // the display transform only applies to 8 bit formats, EXR and HDR get the radiance as is
+
+    /// Tone mapping operator [default: clamp]
+    #[arg(long, value_enum)]
+    tonemap: Option<ToneMap>,
+
+    /// Exposure in stops, +1 is twice as bright [default: 0]
+    #[arg(long, allow_negative_numbers = true)]
+    exposure: Option<f32>,
+
+    /// Radiance that comes out white with --tonemap reinhard-extended [default: 4]
+    #[arg(long)]
+    white_point: Option<f32>,
+
+    /// Colour temperature in kelvin of the light that should come out white, e.g. 3200 for tungsten
+    #[arg(long)]
+    white_balance: Option<f32>,
+
+    /// Resume from this checkpoint if it exists, and keep it up to date while rendering.
+    /// A stopped render picks up where it was, and raising --spp adds samples to a finished one
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Seconds between saves of the image and checkpoint while rendering, 0 only saves at the end
+    #[arg(long, default_value_t = 60)]
+    save_every: u64,
+
+    /// GPU to render on, as an index or part of its name. Overrides PATHTRACER_DEVICE
+    #[arg(long)]
+    device: Option<String>,
+
+    /// How rays are traced, auto uses hardware ray tracing when the GPU has it [default: auto]
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+
+    /// Print more, repeat for even more
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tga,
+    Exr,
+    Hdr,
 }
 
-impl MyVertex {
-    pub fn new(x: f32, y: f32) -> Self {
-        return Self {
-            position: [x, y]
+impl OutputFormat {
+    const ALL: [OutputFormat; 6] = [OutputFormat::Png, OutputFormat::Jpeg, OutputFormat::Bmp, OutputFormat::Tga, OutputFormat::Exr, OutputFormat::Hdr];
+
+    fn image_format(self) -> ImageFormat {
+        return match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Bmp => ImageFormat::Bmp,
+            OutputFormat::Tga => ImageFormat::Tga,
+            OutputFormat::Exr => ImageFormat::OpenExr,
+            OutputFormat::Hdr => ImageFormat::Hdr,
         };
     }
+
+    fn from_path(path: &Path) -> Option<Self> {
+        let format = ImageFormat::from_path(path).ok()?;
+        return Self::ALL.into_iter().find(|output_format| output_format.image_format() == format);
+    }
 }
 
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Backend {
+    Auto,
+    Software,
+    Hardware,
+}
 
+impl From<Backend> for TraceBackend {
+    fn from(backend: Backend) -> Self {
+        return match backend {
+            Backend::Auto => TraceBackend::Auto,
+            Backend::Software => TraceBackend::Software,
+            Backend::Hardware => TraceBackend::Hardware,
+        };
+    }
+}
 
-fn main() {
-    let gpu = gpu::GPU::init();
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Sampling {
+    Mis,
+    Bsdf,
+    Light,
+}
 
+impl From<Sampling> for LightSampling {
+    fn from(sampling: Sampling) -> Self {
+        return match sampling {
+            Sampling::Mis => LightSampling::Mis,
+            Sampling::Bsdf => LightSampling::Bsdf,
+            Sampling::Light => LightSampling::Light,
+        };
+    }
+}
 
-    /////////// Output framebuffer
 
-    let image = Image::new(
-        gpu.memory_allocator.clone(),
-        ImageCreateInfo {
-            image_type: ImageType::Dim2d,
-            format: Format::R8G8B8A8_UNORM,
-            extent: [1024, 1024, 1],
-            usage: ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSFER_SRC,
-            ..Default::default()
-        },
-        AllocationCreateInfo {
-            memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
-            ..Default::default()
-        },
-    ).unwrap();
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Selection {
+    Power,
+    Tree,
+}
 
+impl From<Selection> for LightSelection {
+    fn from(selection: Selection) -> Self {
+        return match selection {
+            Selection::Power => LightSelection::Power,
+            Selection::Tree => LightSelection::Tree,
+        };
+    }
+}
 
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Precision {
+    Half,
+    Float,
+}
 
-    ////////// Buffers
+impl From<Precision> for ExrPrecision {
+    fn from(precision: Precision) -> Self {
+        return match precision {
+            Precision::Half => ExrPrecision::Half,
+            Precision::Float => ExrPrecision::Float,
+        };
+    }
+}
 
-    let output_buffer = gpu.buffer_from_iter(
-        (0..1024 * 1024 * 4).map(|_| 0u8),
-        BufferUsage::TRANSFER_DST,
-        MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS
-    );
 
-    let verticies = vec![
-        MyVertex::new(-0.5, -0.5),
-        MyVertex::new(0.0, 0.5),
-        MyVertex::new(0.5, -0.25),
-    ];
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum AovName {
+    Depth,
+    Normal,
+    Albedo,
+    Position,
+    Uv,
+    MaterialId,
+    ObjectId,
+    DiffuseDirect,
+    DiffuseIndirect,
+    SpecularDirect,
+    SpecularIndirect,
+    Emission,
+}
 
-    let vertex_buffer = gpu.buffer_from_iter(
-        verticies,
-        BufferUsage::VERTEX_BUFFER,
-        MemoryTypeFilter::PREFER_DEVICE | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
-    );
+impl From<AovName> for Aov {
+    fn from(aov: AovName) -> Self {
+        return match aov {
+            AovName::Depth => Aov::Depth,
+            AovName::Normal => Aov::Normal,
+            AovName::Albedo => Aov::Albedo,
+            AovName::Position => Aov::Position,
+            AovName::Uv => Aov::Uv,
+            AovName::MaterialId => Aov::MaterialId,
+            AovName::ObjectId => Aov::ObjectId,
+            AovName::DiffuseDirect => Aov::DiffuseDirect,
+            AovName::DiffuseIndirect => Aov::DiffuseIndirect,
+            AovName::SpecularDirect => Aov::SpecularDirect,
+            AovName::SpecularIndirect => Aov::SpecularIndirect,
+            AovName::Emission => Aov::Emission,
+        };
+    }
+}
 
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ToneMap {
+    Clamp,
+    Reinhard,
+    ReinhardExtended,
+    Aces,
+    Agx,
+    Filmic,
+}
 
+impl From<ToneMap> for ToneMapOperator {
+    fn from(tone_map: ToneMap) -> Self {
+        return match tone_map {
+            ToneMap::Clamp => ToneMapOperator::Clamp,
+            ToneMap::Reinhard => ToneMapOperator::Reinhard,
+            ToneMap::ReinhardExtended => ToneMapOperator::ReinhardExtended,
+            ToneMap::Aces => ToneMapOperator::AcesFitted,
+            ToneMap::Agx => ToneMapOperator::AgX,
+            ToneMap::Filmic => ToneMapOperator::Filmic,
+        };
+    }
+}
 
 
-    /////////// Render pass
+impl Args {
+    /// Checks the options clap can't check on its own, exiting with a usage error if they clash
+    fn validate(&self) -> OutputFormat {
+        let mut command = Args::command();
+
+        if let Some(exposure) = self.exposure && !exposure.is_finite() {
+            command.error(ErrorKind::InvalidValue, format!("--exposure {exposure} isn't a number of stops")).exit();
+        }
+
+        if let Some(white_point) = self.white_point && !(white_point > 0.0 && white_point.is_finite()) {
+            command.error(ErrorKind::InvalidValue, format!("--white-point {white_point} has to be positive")).exit();
+        }
+
+        if let Some(white_balance) = self.white_balance && !(1667.0..=25000.0).contains(&white_balance) {
+            command.error(ErrorKind::InvalidValue, format!("--white-balance {white_balance} is outside of 1667K to 25000K")).exit();
+        }
+
+        let from_extension = OutputFormat::from_path(&self.output);
+
+        return match (self.format, from_extension) {
+            (Some(format), Some(extension)) if format != extension => {
+                command.error(
+                    ErrorKind::ArgumentConflict,
+                    format!("--format {format:?} doesn't match the extension of {}", self.output.display())
+                ).exit();
+            }
+            (Some(format), _) => format,
+            (None, Some(extension)) => extension,
+            (None, None) => {
+                command.error(
+                    ErrorKind::InvalidValue,
+                    format!("can't tell the image format of {}, use a known extension or pass --format", self.output.display())
+                ).exit();
+            }
+        };
+    }
 
-    let render_pass = vulkano::single_pass_renderpass!(
-        gpu.device.clone(),
-        attachments: {
-            color: {
-                format: Format::R8G8B8A8_UNORM,
-                samples: 1,
-                load_op: Clear,
-                store_op: Store,
-            },
-        },
-        pass: {
-            color: [color],
-            depth_stencil: {},
-        },
-    ).unwrap();
 
+    /// Overwrites the settings that were given on the command line
+    fn apply(&self, settings: &mut RenderSettings) {
+        settings.width = self.width.unwrap_or(settings.width);
+        settings.height = self.height.unwrap_or(settings.height);
+        settings.samples_per_pixel = self.spp.unwrap_or(settings.samples_per_pixel);
+        settings.samples_per_dispatch = self.spp_per_dispatch.unwrap_or(settings.samples_per_dispatch);
+        settings.max_bounces = self.max_bounces.unwrap_or(settings.max_bounces);
+        settings.seed = self.seed.unwrap_or(settings.seed);
+        settings.light_sampling = self.light_sampling.map_or(settings.light_sampling, LightSampling::from);
+        settings.light_selection = self.light_selection.map_or(settings.light_selection, LightSelection::from);
+
+        // the same AOV twice is only rendered once
+        for aov in self.aov.iter().map(|&aov| Aov::from(aov)) {
+            if !settings.aovs.contains(&aov) {
+                settings.aovs.push(aov);
+            }
+        }
+
+        settings.display.operator = self.tonemap.map_or(settings.display.operator, ToneMapOperator::from);
+        settings.display.exposure = self.exposure.unwrap_or(settings.display.exposure);
+        settings.display.white_point = self.white_point.unwrap_or(settings.display.white_point);
+        settings.display.white_balance = self.white_balance.or(settings.display.white_balance);
+    }
 
 
+    fn log_level(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Error;
+        }
 
-    let view = ImageView::new_default(image.clone()).unwrap();
-    let framebuffer = Framebuffer::new(
-        render_pass.clone(),
-        FramebufferCreateInfo {
-            attachments: vec![view],
-            ..Default::default()
+        return match self.verbose {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        };
+    }
+}
+
+
+
+fn main() {
+    let args = Args::parse();
+    let output_format = args.validate();
+
+    env_logger::Builder::new()
+        .filter_level(args.log_level())
+        .format_timestamp(None)
+        .init();
+
+    let scene = match &args.scene {
+        Some(path) => match Scene::load(path) {
+            Ok(scene) => scene,
+            Err(err) => {
+                log::error!("Failed to load scene: {err}");
+                std::process::exit(1);
+            }
         },
-    ).unwrap();
+        None => Scene::cornell_box(),
+    };
+
+    let mut settings = RenderSettings::default();
+    scene.render_settings.apply(&mut settings);
+    args.apply(&mut settings);
+
 
-    //////// Pipeline
-    
 
-    let vs = shaders::vertex_shader::load(gpu.device.clone()).expect("failed to create shader module");
-    let fs = shaders::fragment_shader::load(gpu.device.clone()).expect("failed to create shader module");
+    let selection = match &args.device {
+        Some(device) => DeviceSelection::parse(device),
+        None => DeviceSelection::from_env(),
+    };
+
+    let gpu = match selection.and_then(|selection| GPU::init_with(&selection)) {
+        Ok(gpu) => gpu,
+        Err(err) => {
+            log::error!("Failed to initialise the GPU: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let renderer = match Renderer::with_backend(gpu, args.backend.map_or(TraceBackend::default(), TraceBackend::from)) {
+        Ok(renderer) => renderer,
+        Err(err) => {
+            log::error!("Failed to create the renderer: {err}");
+            std::process::exit(1);
+        }
+    };
+
 
 
+    let checkpoint = match &args.checkpoint {
+        Some(path) if path.exists() => match Accumulation::load(path) {
+            Ok(accumulation) => Some(accumulation),
+            Err(err) => {
+                log::error!("Failed to load checkpoint {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
 
-    let viewport = Viewport {
-        offset: [0.0, 0.0],
-        extent: [1024.0, 1024.0],
-        depth_range: 0.0..=1.0,
+    let render = match &checkpoint {
+        Some(accumulation) => {
+            log::info!("Resuming from {} spp", accumulation.samples);
+            renderer.resume(&scene, &settings, accumulation)
+        }
+        None => renderer.start(&scene, &settings),
     };
 
-    let pipeline = {
-        // A Vulkan shader can in theory contain multiple entry points, so we have to specify
-        // which one.
-        let vs = vs.entry_point("main").unwrap();
-        let fs = fs.entry_point("main").unwrap();
-
-        let vertex_input_state = MyVertex::per_vertex()
-            .definition(&vs)
-            .unwrap();
-
-        let stages = [
-            PipelineShaderStageCreateInfo::new(vs),
-            PipelineShaderStageCreateInfo::new(fs),
-        ];
-
-        let layout = PipelineLayout::new(
-            gpu.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages(&stages)
-                .into_pipeline_layout_create_info(gpu.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
-
-        GraphicsPipeline::new(gpu.device.clone(), None,
-            GraphicsPipelineCreateInfo {
-                // The stages of our pipeline, we have vertex and fragment stages.
-                stages: stages.into_iter().collect(),
-                // Describes the layout of the vertex input and how should it behave.
-                vertex_input_state: Some(vertex_input_state),
-                // Indicate the type of the primitives (the default is a list of triangles).
-                input_assembly_state: Some(InputAssemblyState::default()),
-                // Set the fixed viewport.
-                viewport_state: Some(ViewportState {
-                    viewports: [viewport].into_iter().collect(),
-                    ..Default::default()
-                }),
-                // Ignore these for now.
-                rasterization_state: Some(RasterizationState::default()),
-                multisample_state: Some(MultisampleState::default()),
-                color_blend_state: Some(ColorBlendState::with_attachment_states(
-                    subpass.num_color_attachments(),
-                    ColorBlendAttachmentState::default(),
-                )),
-                // This graphics pipeline object concerns the first pass of the render pass.
-                subpass: Some(subpass.into()),
-                ..GraphicsPipelineCreateInfo::layout(layout)
-            },
-        )
-        .unwrap()
+    let mut render = match render {
+        Ok(render) => render,
+        Err(err) => {
+            log::error!("Render failed: {err}");
+            std::process::exit(1);
+        }
     };
 
 
 
+    let start = Instant::now();
+    let first_sample = render.samples();
+    let mut last_save = Instant::now();
+
+    while !render.is_done() {
+        if let Err(err) = render.step() {
+            log::error!("Render failed: {err}");
+            std::process::exit(1);
+        }
+
+        if args.save_every > 0 && last_save.elapsed() >= Duration::from_secs(args.save_every) && !render.is_done() {
+            save(&render, &args, output_format);
+            log::info!("Saved {} at {}/{} spp", args.output.display(), render.samples(), settings.samples_per_pixel);
+            last_save = Instant::now();
+        }
+    }
+
+    log::info!(
+        "Traced {}x{} at {} spp in {:.2?}",
+        settings.width,
+        settings.height,
+        render.samples() - first_sample,
+        start.elapsed()
+    );
+
+    save(&render, &args, output_format);
+    log::info!("Saved {}", args.output.display());
+}
+
+
 
-    ///////////// Draw calls
+/// Writes the image and checkpoint as they are so far, exiting if either can't be written
+fn save(render: &ProgressiveRender<'_>, args: &Args, output_format: OutputFormat) {
+    let accumulation = match render.read() {
+        Ok(accumulation) => accumulation,
+        Err(err) => {
+            log::error!("Failed to read back the render: {err}");
+            std::process::exit(1);
+        }
+    };
 
-    let mut builder = AutoCommandBufferBuilder::primary(
-        gpu.command_buffer_allocator.clone(),
-        gpu.queue.queue_family_index(),
-        CommandBufferUsage::OneTimeSubmit,
-    ).unwrap();
+    if let Some(path) = &args.checkpoint && let Err(err) = accumulation.save(path) {
+        log::error!("Failed to save checkpoint {}: {err}", path.display());
+        std::process::exit(1);
+    }
 
-    unsafe {
-        builder
-            .begin_render_pass(
-                RenderPassBeginInfo {
-                    clear_values: vec![Some([0.0, 0.0, 1.0, 1.0].into())],
-                    ..RenderPassBeginInfo::framebuffer(framebuffer.clone())
-                },
-                SubpassBeginInfo {
-                    contents: SubpassContents::Inline,
-                    ..Default::default()
-                },
-            ).unwrap()
+    let (width, height) = (accumulation.width, accumulation.height);
+    let mut aov_layers = accumulation.aov_layers();
+
+    let saved = match output_format {
+        OutputFormat::Exr if !args.separate_aovs => {
+            let layers: Vec<Layer> = std::iter::once(Layer::rgba(accumulation.mean())).chain(aov_layers.drain(..)).collect();
+            output::save_exr(&args.output, width, height, &layers, args.exr_precision.into())
+        }
+        OutputFormat::Exr => output::save_exr(&args.output, width, height, &[Layer::rgba(accumulation.mean())], args.exr_precision.into()),
+        OutputFormat::Hdr => {
+            let rgb = accumulation.mean().chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+            output::save_hdr(&args.output, width, height, rgb)
+        }
+        _ => {
+            let framebuffer = match render.display(&render.settings().display) {
+                Ok(framebuffer) => framebuffer,
+                Err(err) => {
+                    log::error!("Failed to tone map the render: {err}");
+                    std::process::exit(1);
+                }
+            };
+
+            // alpha is always 1, and jpeg can't store it anyway
+            let image = DynamicImage::ImageRgba8(framebuffer.into_image()).to_rgb8();
+            image.save_with_format(&args.output, output_format.image_format())
+                .map_err(|err| OutputError::Image { path: args.output.clone(), source: err })
+        }
+    };
 
-            .bind_pipeline_graphics(pipeline.clone()).unwrap()
-            .bind_vertex_buffers(0, vertex_buffer.clone()).unwrap()
-            .draw(
-                3, 1, 0, 0, // 3 is the number of vertices, 1 is the number of instances
-            ).unwrap()
+    // whatever didn't go into the output gets a file of its own, with the channels unprefixed
+    let saved = saved.and_then(|()| aov_layers.into_iter().try_for_each(|layer| {
+        let path = aov_path(&args.output, &layer.name);
+        let layer = Layer { name: String::new(), ..layer };
+        return output::save_exr(path, width, height, &[layer], args.exr_precision.into());
+    }));
 
-            .end_render_pass(SubpassEndInfo::default()).unwrap()
-            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(image, output_buffer.clone())).unwrap();
+    if let Err(err) = saved {
+        log::error!("Failed to save {err}");
+        std::process::exit(1);
     }
+}
+
 
-    let command_buffer = builder.build().unwrap();
+/// image.png becomes image.depth.exr
+fn aov_path(output: &Path, name: &str) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    return output.with_file_name(format!("{stem}.{name}.exr"));
+}
 
-    //////// Run & Results
-    let start = std::time::Instant::now();
-    // gpu.run(command_buffer);
-    let future = vulkano::sync::now(gpu.device.clone())
-        .then_execute(gpu.queue.clone(), command_buffer)
-        .unwrap()
-        .then_signal_fence_and_flush()
-        .unwrap();
 
-    future.wait(None).unwrap();
-    println!("Done in {:.2?}", start.elapsed());
 
-    let buffer_content = output_buffer.read().unwrap();
-    let image = ImageBuffer::<Rgba<u8>, _>::from_raw(1024, 1024, &buffer_content[..]).unwrap();
-    image.save("image.png").unwrap();
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
 
-    println!("Everything succeeded!");
+    #[test]
+    fn aov_paths_replace_the_extension() {
+        assert_eq!(aov_path(Path::new("image.png"), "depth"), PathBuf::from("image.depth.exr"));
+        assert_eq!(aov_path(Path::new("renders/image.exr"), "normal"), PathBuf::from("renders/image.normal.exr"));
+        assert_eq!(aov_path(Path::new("image"), "uv"), PathBuf::from("image.uv.exr"));
+    }
 }
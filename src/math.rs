@@ -0,0 +1,244 @@
+use std::ops::{Add, AddAssign, Div, Index, Mul, Neg, Sub};
+
+
+
+
+/// Just enough vector maths for building scenes on the CPU
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+
+impl Vec3 {
+    pub const ZERO: Vec3 = Vec3::new(0.0, 0.0, 0.0);
+    pub const ONE: Vec3 = Vec3::new(1.0, 1.0, 1.0);
+    pub const Y: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        return Self { x: x, y: y, z: z };
+    }
+
+    pub const fn splat(value: f32) -> Self {
+        return Self::new(value, value, value);
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        return self.x * other.x + self.y * other.y + self.z * other.z;
+    }
+
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        return Vec3::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        );
+    }
+
+    pub fn length(self) -> f32 {
+        return self.dot(self).sqrt();
+    }
+
+    pub fn normalize(self) -> Vec3 {
+        return self / self.length();
+    }
+
+    pub fn min(self, other: Vec3) -> Vec3 {
+        return Vec3::new(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z));
+    }
+
+    pub fn max(self, other: Vec3) -> Vec3 {
+        return Vec3::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z));
+    }
+
+    /// Padded out to a vec4 for std430 buffers
+    pub fn extend(self, w: f32) -> [f32; 4] {
+        return [self.x, self.y, self.z, w];
+    }
+}
+
+
+impl From<[f32; 3]> for Vec3 {
+    fn from(value: [f32; 3]) -> Self {
+        return Vec3::new(value[0], value[1], value[2]);
+    }
+}
+
+impl From<Vec3> for [f32; 3] {
+    fn from(value: Vec3) -> Self {
+        return [value.x, value.y, value.z];
+    }
+}
+
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, index: usize) -> &f32 {
+        return match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of range: {index}"),
+        };
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+
+    fn add(self, other: Vec3) -> Vec3 {
+        return Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z);
+    }
+}
+
+impl AddAssign for Vec3 {
+    fn add_assign(&mut self, other: Vec3) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+
+    fn sub(self, other: Vec3) -> Vec3 {
+        return Vec3::new(self.x - other.x, self.y - other.y, self.z - other.z);
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, scale: f32) -> Vec3 {
+        return Vec3::new(self.x * scale, self.y * scale, self.z * scale);
+    }
+}
+
+impl Mul<Vec3> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, other: Vec3) -> Vec3 {
+        return Vec3::new(self.x * other.x, self.y * other.y, self.z * other.z);
+    }
+}
+
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, scale: f32) -> Vec3 {
+        return Vec3::new(self.x / scale, self.y / scale, self.z / scale);
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+
+    fn neg(self) -> Vec3 {
+        return Vec3::new(-self.x, -self.y, -self.z);
+    }
+}
+
+
+
+
+/// Column major 4x4 matrix, the same layout as glTF and GLSL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat4 {
+    pub columns: [[f32; 4]; 4],
+}
+
+
+impl Mat4 {
+    pub const IDENTITY: Mat4 = Mat4 {
+        columns: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]
+    };
+
+    pub fn from_columns(columns: [[f32; 4]; 4]) -> Self {
+        return Self { columns: columns };
+    }
+
+    fn column(&self, index: usize) -> Vec3 {
+        let column = self.columns[index];
+        return Vec3::new(column[0], column[1], column[2]);
+    }
+
+    pub fn transform_point(&self, point: Vec3) -> Vec3 {
+        return self.transform_vector(point) + self.column(3);
+    }
+
+    pub fn transform_vector(&self, vector: Vec3) -> Vec3 {
+        return self.column(0) * vector.x + self.column(1) * vector.y + self.column(2) * vector.z;
+    }
+
+    /// Transforms a normal by the inverse transpose of the upper 3x3, so it stays perpendicular
+    /// to the surface under non-uniform scale. The result isn't normalised.
+    pub fn transform_normal(&self, normal: Vec3) -> Vec3 {
+        let (c0, c1, c2) = (self.column(0), self.column(1), self.column(2));
+
+        // the cofactor matrix is the inverse transpose scaled by the determinant,
+        // so only the sign of the determinant needs fixing up
+        let cofactor = Mat4::from_columns([
+            c1.cross(c2).extend(0.0),
+            c2.cross(c0).extend(0.0),
+            c0.cross(c1).extend(0.0),
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let determinant = c0.dot(c1.cross(c2));
+
+        return cofactor.transform_vector(normal) * determinant.signum();
+    }
+
+
+    /// Inverse of an affine transform (the bottom row is ignored), None when it squashes space flat
+    pub fn affine_inverse(&self) -> Option<Mat4> {
+        let (c0, c1, c2) = (self.column(0), self.column(1), self.column(2));
+        let determinant = c0.dot(c1.cross(c2));
+
+        if determinant.abs() <= f32::EPSILON * f32::EPSILON || !determinant.is_finite() {
+            return None;
+        }
+
+        // the rows of the inverse 3x3 are the cross products of the columns over the determinant
+        let rows = [c1.cross(c2) / determinant, c2.cross(c0) / determinant, c0.cross(c1) / determinant];
+        let translation = self.column(3);
+
+        let mut inverse = Mat4::IDENTITY;
+        for (row, inverse_row) in rows.iter().enumerate() {
+            for column in 0..3 {
+                inverse.columns[column][row] = inverse_row[column];
+            }
+            inverse.columns[3][row] = -inverse_row.dot(translation);
+        }
+
+        return Some(inverse);
+    }
+
+
+    /// The top three rows, the layout Vulkan uses for instance transforms
+    pub fn rows_3x4(&self) -> [[f32; 4]; 3] {
+        return [0, 1, 2].map(|row| [0, 1, 2, 3].map(|column| self.columns[column][row]));
+    }
+}
+
+
+impl Mul<Mat4> for Mat4 {
+    type Output = Mat4;
+
+    fn mul(self, other: Mat4) -> Mat4 {
+        let mut columns = [[0.0; 4]; 4];
+
+        for (column, other_column) in columns.iter_mut().zip(other.columns) {
+            for (row, value) in column.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.columns[k][row] * other_column[k]).sum();
+            }
+        }
+
+        return Mat4::from_columns(columns);
+    }
+}
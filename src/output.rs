@@ -0,0 +1,258 @@
+//! Linear, scene referred image files for compositing, as opposed to the 8 bit Framebuffer.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, LayerAttributes, SmallVec, WritableImage, f16};
+use image::{ImageFormat, Rgb32FImage};
+
+
+
+
+#[derive(Debug)]
+pub enum OutputError {
+    Exr { path: PathBuf, source: exr::error::Error },
+    Image { path: PathBuf, source: image::ImageError },
+    /// The layers don't fit the image, e.g. too few pixels for their channels
+    InvalidLayer(String),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            OutputError::Exr { path, source } => write!(f, "{}: {source}", path.display()),
+            OutputError::Image { path, source } => write!(f, "{}: {source}", path.display()),
+            OutputError::InvalidLayer(reason) => write!(f, "invalid layer: {reason}"),
+        };
+    }
+}
+
+impl Error for OutputError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            OutputError::Exr { source, .. } => Some(source),
+            OutputError::Image { source, .. } => Some(source),
+            OutputError::InvalidLayer(_) => None,
+        };
+    }
+}
+
+
+
+
+/// How EXR channels are stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExrPrecision {
+    /// 16 bit floats, plenty for colour and half the size
+    #[default]
+    Half,
+    /// 32 bit floats, for data like depth that needs the range
+    Float,
+}
+
+
+/// A group of channels in an output image, like the beauty pass or the albedo
+#[derive(Clone, Debug)]
+pub struct Layer {
+    /// Prefix of the channel names, empty for the main RGBA layer compositors show by default
+    pub name: String,
+    /// Channel names in the order they are interleaved in pixels, like R, G, B
+    pub channels: Vec<String>,
+    /// Linear values with the channels interleaved, rows from top to bottom
+    pub pixels: Vec<f32>,
+}
+
+
+impl Layer {
+    /// The main layer, with the usual R, G, B and A channels
+    pub fn rgba(pixels: Vec<f32>) -> Self {
+        return Self {
+            name: String::new(),
+            channels: ["R", "G", "B", "A"].map(String::from).to_vec(),
+            pixels: pixels
+        };
+    }
+
+
+    /// What the channel is called in the file, prefixed with the layer like albedo.R
+    fn channel_name(&self, channel: &str) -> String {
+        if self.name.is_empty() {
+            return channel.to_string();
+        }
+
+        return format!("{}.{channel}", self.name);
+    }
+}
+
+
+
+
+/// Writes every layer into a single part EXR, with the channels of named layers prefixed
+/// by the layer's name, which is how compositors expect layers
+pub fn save_exr(path: impl AsRef<Path>, width: u32, height: u32, layers: &[Layer], precision: ExrPrecision) -> Result<(), OutputError> {
+    let path = path.as_ref();
+    let pixel_count = width as usize * height as usize;
+
+    let mut channels = Vec::new();
+
+    for layer in layers {
+        if layer.pixels.len() != pixel_count * layer.channels.len() {
+            return Err(OutputError::InvalidLayer(format!(
+                "layer '{}' has {} values for {}x{} pixels of {} channels",
+                layer.name,
+                layer.pixels.len(),
+                width,
+                height,
+                layer.channels.len()
+            )));
+        }
+
+        for (index, channel) in layer.channels.iter().enumerate() {
+            let values = layer.pixels.iter().skip(index).step_by(layer.channels.len()).copied();
+
+            let samples = match precision {
+                ExrPrecision::Half => FlatSamples::F16(values.map(f16::from_f32).collect()),
+                ExrPrecision::Float => FlatSamples::F32(values.collect()),
+            };
+
+            channels.push(AnyChannel::new(layer.channel_name(channel).as_str(), samples));
+        }
+    }
+
+    let image = exr::image::Image::from_layer(exr::image::Layer::new(
+        (width as usize, height as usize),
+        LayerAttributes::default(),
+        Encoding::SMALL_LOSSLESS,
+        AnyChannels::sort(SmallVec::from_vec(channels))
+    ));
+
+    return image.write().to_file(path).map_err(|err| OutputError::Exr { path: path.to_path_buf(), source: err });
+}
+
+
+/// Writes a Radiance RGBE file, which only has room for RGB
+pub fn save_hdr(path: impl AsRef<Path>, width: u32, height: u32, rgb: Vec<f32>) -> Result<(), OutputError> {
+    let path = path.as_ref();
+    let length = rgb.len();
+
+    let Some(image) = Rgb32FImage::from_raw(width, height, rgb) else {
+        return Err(OutputError::InvalidLayer(format!("{length} values for {width}x{height} RGB pixels")));
+    };
+
+    return image.save_with_format(path, ImageFormat::Hdr).map_err(|err| OutputError::Image { path: path.to_path_buf(), source: err });
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use exr::prelude::{ReadChannels, ReadLayers};
+
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("output-{}-{name}", std::process::id()));
+    }
+
+
+    /// Channel names and samples of the file's only layer, as floats
+    fn read_exr(path: &Path) -> Vec<(String, FlatSamples)> {
+        let image = exr::image::read::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .all_layers()
+            .all_attributes()
+            .from_file(path)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(image.layer_data.len(), 1);
+        assert_eq!(image.layer_data[0].size, exr::math::Vec2(2, 1));
+
+        return image.layer_data[0].channel_data.list.iter()
+            .map(|channel| (channel.name.to_string(), channel.sample_data.clone()))
+            .collect();
+    }
+
+
+    fn layers() -> Vec<Layer> {
+        return vec![
+            Layer::rgba(vec![0.25, 0.5, 1.0, 1.0, 2.0, 4.0, 8.0, 0.0]),
+            Layer { name: "depth".to_string(), channels: vec!["Z".to_string()], pixels: vec![1.5, 1234.5678] },
+            Layer { name: "normal".to_string(), channels: ["X", "Y", "Z"].map(String::from).to_vec(), pixels: vec![0.0, 1.0, 0.0, -1.0, 0.0, 0.5] },
+        ];
+    }
+
+
+    #[test]
+    fn exr_layers_become_prefixed_channels() {
+        let path = temp_path("layers.exr");
+        save_exr(&path, 2, 1, &layers(), ExrPrecision::Float).unwrap();
+
+        let channels = read_exr(&path);
+        let names: Vec<&str> = channels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["A", "B", "G", "R", "depth.Z", "normal.X", "normal.Y", "normal.Z"]);
+
+        let samples = |name: &str| match &channels.iter().find(|(channel, _)| channel == name).unwrap().1 {
+            FlatSamples::F32(samples) => samples.clone(),
+            samples => panic!("{name} isn't 32 bit: {samples:?}"),
+        };
+
+        assert_eq!(samples("R"), [0.25, 2.0]);
+        assert_eq!(samples("G"), [0.5, 4.0]);
+        assert_eq!(samples("B"), [1.0, 8.0]);
+        assert_eq!(samples("A"), [1.0, 0.0]);
+        assert_eq!(samples("depth.Z"), [1.5, 1234.5678]);
+        assert_eq!(samples("normal.X"), [0.0, -1.0]);
+        assert_eq!(samples("normal.Z"), [0.0, 0.5]);
+    }
+
+
+    #[test]
+    fn half_precision_exrs_store_f16() {
+        let path = temp_path("half.exr");
+        save_exr(&path, 2, 1, &layers(), ExrPrecision::Half).unwrap();
+
+        for (name, samples) in read_exr(&path) {
+            let FlatSamples::F16(samples) = samples else {
+                panic!("{name} isn't 16 bit: {samples:?}");
+            };
+
+            if name == "R" {
+                assert_eq!(samples, [f16::from_f32(0.25), f16::from_f32(2.0)]);
+            }
+        }
+    }
+
+
+    #[test]
+    fn layers_of_the_wrong_size_are_rejected() {
+        let path = temp_path("invalid.exr");
+        let layer = Layer { name: "depth".to_string(), channels: vec!["Z".to_string()], pixels: vec![1.0; 3] };
+
+        let result = save_exr(&path, 2, 1, &[layer], ExrPrecision::Half);
+        assert!(matches!(result, Err(OutputError::InvalidLayer(_))), "{result:?}");
+        assert!(!path.exists());
+
+        let result = save_hdr(temp_path("invalid.hdr"), 2, 1, vec![1.0; 5]);
+        assert!(matches!(result, Err(OutputError::InvalidLayer(_))), "{result:?}");
+    }
+
+
+    #[test]
+    fn hdr_round_trips() {
+        let path = temp_path("image.hdr");
+        let rgb = vec![1.0, 0.5, 0.25, 16.0, 8.0, 4.0];
+        save_hdr(&path, 2, 1, rgb.clone()).unwrap();
+
+        let image = image::open(&path).unwrap().into_rgb32f();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.into_raw(), rgb);
+    }
+}
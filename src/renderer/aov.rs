@@ -0,0 +1,137 @@
+/// Arbitrary output variables, extra passes rendered alongside the beauty image for compositing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Aov {
+    /// Distance from the camera along the primary ray, infinite where it misses
+    Depth,
+    /// World space shading normal
+    Normal,
+    /// Surface colour without any lighting
+    Albedo,
+    /// World space position
+    Position,
+    /// Texture coordinates
+    Uv,
+    /// Index of the material, -1 where the ray misses
+    MaterialId,
+    /// 0 for the scene's loose triangles and 1 + the index of an instance, -1 where the ray misses
+    ObjectId,
+    /// Light reaching the camera after one diffuse bounce
+    DiffuseDirect,
+    /// Light reaching the camera after a diffuse first bounce and more after it
+    DiffuseIndirect,
+    /// The same as the diffuse ones for a specular first bounce
+    SpecularDirect,
+    SpecularIndirect,
+    /// Emissive surfaces seen directly by the camera
+    Emission,
+}
+
+
+impl Aov {
+    pub const ALL: [Aov; 12] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Position,
+        Aov::Uv,
+        Aov::MaterialId,
+        Aov::ObjectId,
+        Aov::DiffuseDirect,
+        Aov::DiffuseIndirect,
+        Aov::SpecularDirect,
+        Aov::SpecularIndirect,
+        Aov::Emission,
+    ];
+
+
+    /// Its bit in the shader's aov_mask, the AOV_ constants in shaders::path_tracer
+    pub(super) fn index(self) -> u32 {
+        return Self::ALL.iter().position(|&aov| aov == self).expect("every AOV is in ALL") as u32;
+    }
+
+
+    /// Layer name in EXR files and suffix of separate files
+    pub fn name(self) -> &'static str {
+        return match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+            Aov::DiffuseDirect => "diffuse_direct",
+            Aov::DiffuseIndirect => "diffuse_indirect",
+            Aov::SpecularDirect => "specular_direct",
+            Aov::SpecularIndirect => "specular_indirect",
+            Aov::Emission => "emission",
+        };
+    }
+
+
+    /// Names of the channels, which are the first ones of the RGBA the shader writes
+    pub fn channels(self) -> &'static [&'static str] {
+        return match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Uv => &["U", "V"],
+            Aov::MaterialId | Aov::ObjectId => &["ID"],
+            _ => &["R", "G", "B"],
+        };
+    }
+
+
+    /// Whether it's the mean of every sample like the beauty image. Depth and IDs would be meaningless
+    /// blended across edges, so they are taken from a single sample instead
+    pub fn is_filtered(self) -> bool {
+        return !matches!(self, Aov::Depth | Aov::MaterialId | Aov::ObjectId);
+    }
+}
+
+
+/// The AOVs in the order the shader stores them, each one once
+pub(super) fn in_shader_order(aovs: &[Aov]) -> Vec<Aov> {
+    return Aov::ALL.into_iter().filter(|aov| aovs.contains(aov)).collect();
+}
+
+
+pub(super) fn mask(aovs: &[Aov]) -> u32 {
+    return aovs.iter().fold(0, |mask, aov| mask | 1 << aov.index());
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn shader_order_sorts_and_dedups() {
+        let aovs = [Aov::Emission, Aov::Depth, Aov::Uv, Aov::Depth, Aov::Emission];
+        assert_eq!(in_shader_order(&aovs), [Aov::Depth, Aov::Uv, Aov::Emission]);
+        assert_eq!(in_shader_order(&[]), []);
+    }
+
+
+    #[test]
+    fn mask_has_a_bit_per_aov() {
+        assert_eq!(mask(&[]), 0);
+        assert_eq!(mask(&[Aov::Depth, Aov::Emission, Aov::Depth]), 1 | 1 << 11);
+        assert_eq!(mask(&Aov::ALL), (1 << Aov::ALL.len()) - 1);
+    }
+
+
+    #[test]
+    fn indices_match_the_shader() {
+        let shader = include_str!("../shaders/path_tracer.comp");
+
+        for aov in Aov::ALL {
+            let define = format!("#define AOV_{} {}u\n", aov.name().to_uppercase(), aov.index());
+            assert!(shader.contains(&define), "the shader is missing {define:?}");
+        }
+
+        assert_eq!(shader.matches("#define AOV_").count(), Aov::ALL.len());
+    }
+}
@@ -0,0 +1,76 @@
+use vulkano::buffer::BufferContents;
+
+use crate::scene::{Camera, Projection};
+
+
+
+
+// must match the Camera uniform block in shaders::path_tracer, std140
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct GPUCamera {
+    position: [f32; 4],
+    forward: [f32; 4],
+    /// Right and up are scaled to the edges of the image: at a distance of 1 along forward for perspective,
+    /// in scene units for orthographic, relative to the image circle for fisheye and not at all for equirectangular
+    right: [f32; 4],
+    up: [f32; 4],
+    /// In scene units, zero for a pinhole
+    aperture_radius: f32,
+    focus_distance: f32,
+    /// Fewer than 3 is a round aperture
+    blades: u32,
+    /// In radians
+    blade_rotation: f32,
+    /// The PROJECTION_ constant in the shader
+    projection: u32,
+    /// Fisheye angle from the view direction to the edge of the image circle, in radians
+    max_angle: f32,
+    /// Zero renders a single eye
+    ipd: f32,
+    _padding: u32,
+}
+
+
+impl GPUCamera {
+    pub(super) fn new(camera: &Camera, width: u32, height: u32) -> Self {
+        let forward = (camera.look_at - camera.position).normalize();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+
+        let aspect = width as f32 / height as f32;
+
+        let (projection, half_height, max_angle, ipd) = match camera.projection {
+            Projection::Perspective => (0, (camera.fov.to_radians() / 2.0).tan(), 0.0, 0.0),
+            Projection::Orthographic { height } => (1, height / 2.0, 0.0, 0.0),
+            Projection::Fisheye { fov } => (2, 1.0, fov.to_radians() / 2.0, 0.0),
+            Projection::Equirectangular { ipd } => (3, 1.0, 0.0, ipd.unwrap_or(0.0)),
+        };
+
+        // the panorama covers the whole sphere whatever the aspect ratio
+        let half_width = match camera.projection {
+            Projection::Equirectangular { .. } => 1.0,
+            _ => half_height * aspect,
+        };
+
+        // only the perspective projection has a lens
+        let lens = camera.lens.filter(|_| camera.projection == Projection::Perspective);
+        let aperture_radius = if lens.is_some() { camera.aperture_radius() } else { 0.0 };
+        let lens = lens.unwrap_or_default();
+
+        return Self {
+            position: camera.position.extend(0.0),
+            forward: forward.extend(0.0),
+            right: (right * half_width).extend(0.0),
+            up: (up * half_height).extend(0.0),
+            aperture_radius: aperture_radius,
+            focus_distance: lens.focus_distance,
+            blades: lens.blades,
+            blade_rotation: lens.blade_rotation.to_radians(),
+            projection: projection,
+            max_angle: max_angle,
+            ipd: ipd,
+            _padding: 0
+        };
+    }
+}
@@ -0,0 +1,226 @@
+use vulkano::buffer::BufferContents;
+
+
+
+
+/// Curve that squeezes HDR radiance into the displayable range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    /// Everything above 1 clips
+    #[default]
+    Clamp,
+    /// x / (1 + x), never quite reaches white
+    Reinhard,
+    /// Reinhard that reaches white at DisplayTransform::white_point
+    ReinhardExtended,
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    AcesFitted,
+    /// Troy Sobotka's AgX, desaturating bright colours instead of skewing their hue
+    AgX,
+    /// John Hable's filmic curve from Uncharted 2
+    Filmic,
+}
+
+
+impl ToneMapOperator {
+    /// The OPERATOR_ constant in shaders::display
+    fn index(self) -> u32 {
+        return match self {
+            ToneMapOperator::Clamp => 0,
+            ToneMapOperator::Reinhard => 1,
+            ToneMapOperator::ReinhardExtended => 2,
+            ToneMapOperator::AcesFitted => 3,
+            ToneMapOperator::AgX => 4,
+            ToneMapOperator::Filmic => 5,
+        };
+    }
+}
+
+
+/// How linear radiance becomes 8 bit sRGB: exposure and white balance, then the tone curve, then the sRGB OETF
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayTransform {
+    pub operator: ToneMapOperator,
+    /// In stops, every +1 doubles the brightness
+    pub exposure: f32,
+    /// Radiance that maps to white with ReinhardExtended, ignored by the others
+    pub white_point: f32,
+    /// Colour temperature in kelvin of the light that should come out white, None leaves colours as rendered
+    pub white_balance: Option<f32>,
+}
+
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        return Self {
+            operator: ToneMapOperator::default(),
+            exposure: 0.0,
+            white_point: 4.0,
+            white_balance: None
+        };
+    }
+}
+
+
+
+
+// must match the push constant block in shaders::display
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct DisplayPushConstants {
+    /// Columns of the white balance matrix, scaled by the exposure
+    color_matrix: [[f32; 4]; 3],
+    width: u32,
+    height: u32,
+    tone_map_operator: u32,
+    white_point: f32,
+}
+
+
+impl DisplayTransform {
+    pub(super) fn push_constants(&self, width: u32, height: u32) -> DisplayPushConstants {
+        let scale = self.exposure.exp2();
+        let matrix = self.white_balance.map_or(IDENTITY, white_balance);
+
+        let column = |column: usize| [matrix[0][column] * scale, matrix[1][column] * scale, matrix[2][column] * scale, 0.0];
+
+        return DisplayPushConstants {
+            color_matrix: [column(0), column(1), column(2)],
+            width: width,
+            height: height,
+            tone_map_operator: self.operator.index(),
+            white_point: self.white_point.max(1e-3)
+        };
+    }
+}
+
+
+
+
+/////////// White balance
+
+/// Row major
+type Mat3 = [[f32; 3]; 3];
+
+const IDENTITY: Mat3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// linear Rec. 709 primaries with a D65 white, the renderer's working space
+const XYZ_FROM_RGB: Mat3 = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.072175],
+    [0.0193339, 0.119192, 0.9503041],
+];
+
+const RGB_FROM_XYZ: Mat3 = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.041556],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+// Bradford cone response, for adapting from one white to another
+const LMS_FROM_XYZ: Mat3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+const XYZ_FROM_LMS: Mat3 = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+const D65: [f32; 2] = [0.3127, 0.3290];
+
+
+fn multiply(a: &Mat3, b: &Mat3) -> Mat3 {
+    return std::array::from_fn(|row| std::array::from_fn(|column| (0..3).map(|i| a[row][i] * b[i][column]).sum()));
+}
+
+
+fn transform(matrix: &Mat3, vector: [f32; 3]) -> [f32; 3] {
+    return std::array::from_fn(|row| (0..3).map(|i| matrix[row][i] * vector[i]).sum());
+}
+
+
+/// Chromaticity of a light at the given colour temperature: on the Planckian locus below 4000K, using the
+/// cubic fit from Kang et al. 2002, and on the CIE daylight locus from there up, using the CIE's own polynomials.
+/// The two loci don't meet, so the white jumps by about 0.007 in y at 4000K
+fn white_of(temperature: f32) -> [f32; 2] {
+    let t = temperature.clamp(1667.0, 25000.0) as f64;
+
+    let x = match t {
+        t if t < 4000.0 => -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910,
+        t if t <= 7000.0 => -4.6070e9 / t.powi(3) + 2.9678e6 / t.powi(2) + 0.09911e3 / t + 0.244063,
+        t => -2.0064e9 / t.powi(3) + 1.9018e6 / t.powi(2) + 0.24748e3 / t + 0.237040,
+    };
+
+    let y = match t {
+        t if t < 2222.0 => -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683,
+        t if t < 4000.0 => -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867,
+        _ => -3.0 * x * x + 2.870 * x - 0.275,
+    };
+
+    return [x as f32, y as f32];
+}
+
+
+/// Adapts a light of the given temperature to D65, so it comes out white in Rec. 709
+fn white_balance(temperature: f32) -> Mat3 {
+    let to_lms = |[x, y]: [f32; 2]| transform(&LMS_FROM_XYZ, [x / y, 1.0, (1.0 - x - y) / y]);
+
+    let source = to_lms(white_of(temperature));
+    let target = to_lms(D65);
+    let scale = [
+        [target[0] / source[0], 0.0, 0.0],
+        [0.0, target[1] / source[1], 0.0],
+        [0.0, 0.0, target[2] / source[2]],
+    ];
+
+    let adapt = multiply(&XYZ_FROM_LMS, &multiply(&scale, &LMS_FROM_XYZ));
+    return multiply(&RGB_FROM_XYZ, &multiply(&adapt, &XYZ_FROM_RGB));
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < tolerance), "{a:?} != {b:?}");
+    }
+
+
+    #[test]
+    fn white_of_matches_the_cie_illuminants() {
+        // illuminant A is a 2856K blackbody, D65 is daylight at 6504K
+        let [x, y] = white_of(2856.0);
+        assert!((x - 0.44757).abs() < 1e-3 && (y - 0.40745).abs() < 1e-3, "{x}, {y}");
+
+        let [x, y] = white_of(6504.0);
+        assert!((x - 0.31271).abs() < 1e-3 && (y - 0.32902).abs() < 1e-3, "{x}, {y}");
+    }
+
+
+    #[test]
+    fn d65_needs_no_white_balance() {
+        let matrix = white_balance(6504.0);
+        for row in 0..3 {
+            assert_close(matrix[row], IDENTITY[row], 1e-2);
+        }
+    }
+
+
+    #[test]
+    fn the_source_white_becomes_white() {
+        for temperature in [2000.0, 3200.0, 5000.0, 10000.0] {
+            let [x, y] = white_of(temperature);
+            let white = transform(&RGB_FROM_XYZ, [x / y, 1.0, (1.0 - x - y) / y]);
+
+            assert_close(transform(&white_balance(temperature), white), [1.0; 3], 1e-3);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::f32::consts::PI;
+
+use crate::math::Vec3;
+use crate::scene::EnvironmentMap;
+
+use super::lights::luminance;
+
+
+
+
+/// An environment map the way the shader samples it, as a piecewise constant distribution over its pixels
+pub(super) struct Environment {
+    pub width: u32,
+    pub height: u32,
+    /// RGB times the map's intensity, rows from top to bottom
+    pub texels: Vec<f32>,
+    /// Each row's conditional CDF over its pixels, then the marginal CDF over the rows.
+    /// Every CDF holds the chance of picking that pixel or any before it, ending in 1
+    pub cdf: Vec<f32>,
+    /// Luminance averaged over the sphere, zero for a black map
+    pub average_luminance: f32,
+}
+
+
+impl Environment {
+    pub(super) fn new(map: &EnvironmentMap) -> Self {
+        let width = map.width as usize;
+        let height = map.height as usize;
+
+        let texels: Vec<f32> = map.pixels.iter().map(|value| value * map.intensity).collect();
+
+        // rows near the poles cover less of the sphere, so their pixels are less likely to be seen
+        let mut cdf = Vec::with_capacity(width * height + height);
+        let mut row_sums = Vec::with_capacity(height);
+        let mut solid_angle = 0.0;
+
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let row = &texels[y * width * 3..(y + 1) * width * 3];
+
+            let start = cdf.len();
+            let mut sum = 0.0;
+            for texel in row.chunks_exact(3) {
+                sum += luminance(Vec3::new(texel[0], texel[1], texel[2])).max(0.0) * sin_theta;
+                cdf.push(sum);
+            }
+
+            normalize(&mut cdf[start..], sum);
+            row_sums.push(sum);
+            solid_angle += sin_theta * width as f32;
+        }
+
+        let start = cdf.len();
+        let mut total = 0.0;
+        for sum in &row_sums {
+            total += sum;
+            cdf.push(total);
+        }
+
+        normalize(&mut cdf[start..], total);
+
+        return Self {
+            width: map.width,
+            height: map.height,
+            texels: texels,
+            cdf: cdf,
+            average_luminance: if solid_angle > 0.0 { total / solid_angle } else { 0.0 }
+        };
+    }
+}
+
+
+/// Divides a running sum by its total, or makes it uniform when there's nothing to pick by
+fn normalize(cdf: &mut [f32], total: f32) {
+    let count = cdf.len() as f32;
+
+    for (index, value) in cdf.iter_mut().enumerate() {
+        *value = if total > 0.0 { *value / total } else { (index + 1) as f32 / count };
+    }
+
+    // rounding mustn't leave a gap at the end for the shader to fall into
+    if let Some(last) = cdf.last_mut() {
+        *last = 1.0;
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn map(width: u32, height: u32, pixels: Vec<f32>) -> EnvironmentMap {
+        return EnvironmentMap {
+            width: width,
+            height: height,
+            pixels: pixels,
+            rotation: 0.0,
+            intensity: 1.0
+        };
+    }
+
+
+    /// Every row's CDF and then the marginal one
+    fn cdfs(environment: &Environment) -> Vec<&[f32]> {
+        let width = environment.width as usize;
+        let height = environment.height as usize;
+
+        let mut cdfs: Vec<&[f32]> = environment.cdf[..width * height].chunks_exact(width).collect();
+        cdfs.push(&environment.cdf[width * height..]);
+        return cdfs;
+    }
+
+
+    fn check_cdfs(environment: &Environment) {
+        assert_eq!(environment.cdf.len(), (environment.width * environment.height + environment.height) as usize);
+
+        for cdf in cdfs(environment) {
+            assert!(cdf.windows(2).all(|pair| pair[0] <= pair[1]), "{cdf:?} isn't increasing");
+            assert!(cdf[0] >= 0.0);
+            assert_eq!(*cdf.last().unwrap(), 1.0);
+        }
+    }
+
+
+    #[test]
+    fn cdfs_end_at_one() {
+        let pixels = (0..16 * 8 * 3).map(|i| ((i * 37) % 11) as f32 * 0.3).collect();
+        let environment = Environment::new(&map(16, 8, pixels));
+
+        check_cdfs(&environment);
+        assert!(environment.average_luminance > 0.0);
+    }
+
+
+    #[test]
+    fn black_maps_are_uniform() {
+        let environment = Environment::new(&map(4, 2, vec![0.0; 4 * 2 * 3]));
+
+        check_cdfs(&environment);
+        assert_eq!(cdfs(&environment)[0], &[0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(environment.average_luminance, 0.0);
+    }
+
+
+    #[test]
+    fn a_single_bright_texel_takes_every_sample() {
+        let mut pixels = vec![0.0; 8 * 4 * 3];
+        pixels[(2 * 8 + 5) * 3..(2 * 8 + 6) * 3].copy_from_slice(&[10.0, 10.0, 10.0]);
+        let environment = Environment::new(&map(8, 4, pixels));
+
+        check_cdfs(&environment);
+
+        let cdfs = cdfs(&environment);
+        assert_eq!(cdfs[4], &[0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(cdfs[2], &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        // rows without light stay uniform instead of dividing by zero
+        assert_eq!(cdfs[0][0], 0.125);
+    }
+
+    #[test]
+    fn rows_are_weighted_by_their_solid_angle() {
+        let environment = Environment::new(&map(4, 4, vec![1.0; 4 * 4 * 3]));
+        check_cdfs(&environment);
+
+        let cdfs = cdfs(&environment);
+        let sin_thetas: Vec<f32> = (0..4).map(|y| (PI * (y as f32 + 0.5) / 4.0).sin()).collect();
+        let total: f32 = sin_thetas.iter().sum();
+
+        let mut expected = 0.0;
+        for (y, sin_theta) in sin_thetas.iter().enumerate() {
+            expected += sin_theta / total;
+            assert!((cdfs[4][y] - expected).abs() < 1e-6, "row {y}: {} against {expected}", cdfs[4][y]);
+            assert!(cdfs[y].iter().zip([0.25, 0.5, 0.75, 1.0]).all(|(a, b)| (a - b).abs() < 1e-6), "{:?} isn't uniform", cdfs[y]);
+        }
+
+        // the rows near the poles are less likely than the ones at the horizon
+        assert!(cdfs[4][0] < cdfs[4][1] - cdfs[4][0]);
+
+        // a white map is as bright from every direction
+        assert!((environment.average_luminance - 1.0).abs() < 1e-5, "{}", environment.average_luminance);
+    }
+}
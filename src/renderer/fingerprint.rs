@@ -0,0 +1,286 @@
+//! A hash of everything that decides what a render converges to, so a checkpoint is only resumed with the scene
+//! and settings it was started with. FNV-1a over the data itself: floats by their bits, images as their bytes
+//! and enums by a fixed number per variant, so it only changes when the scene does.
+
+use crate::math::{Mat4, Vec3};
+use crate::scene::{Bsdf, Camera, EnvironmentMap, Instance, Light, Material, Projection, Scene, Texture, Triangle};
+
+use super::RenderSettings;
+
+
+
+
+/// Of the scene and the settings besides the image size, AOVs and seed, which Renderer::resume checks on their own.
+/// The scene file's render settings only matter through settings
+pub(super) fn fingerprint(scene: &Scene, settings: &RenderSettings) -> u64 {
+    let mut hash = Fnv::new();
+
+    hash.camera(&scene.camera);
+
+    hash.count(scene.triangles.len());
+    scene.triangles.iter().for_each(|triangle| hash.triangle(triangle));
+
+    hash.count(scene.meshes.len());
+    for mesh in &scene.meshes {
+        hash.count(mesh.triangles.len());
+        mesh.triangles.iter().for_each(|triangle| hash.triangle(triangle));
+    }
+
+    hash.count(scene.instances.len());
+    scene.instances.iter().for_each(|instance| hash.instance(instance));
+
+    hash.count(scene.materials.len());
+    scene.materials.iter().for_each(|material| hash.material(material));
+
+    hash.count(scene.textures.len());
+    scene.textures.iter().for_each(|texture| hash.texture(texture));
+
+    hash.count(scene.all_lights().count());
+    scene.all_lights().for_each(|light| hash.light(light));
+
+    hash.vec3(scene.background);
+    match &scene.environment {
+        Some(environment) => {
+            hash.u32(1);
+            hash.environment(environment);
+        }
+        None => hash.u32(0),
+    }
+
+    hash.u32(settings.max_bounces);
+    hash.u32(settings.light_sampling.index());
+    hash.u32(settings.light_selection.index());
+
+    return hash.0;
+}
+
+
+
+
+struct Fnv(u64);
+
+
+impl Fnv {
+    fn new() -> Self {
+        return Self(0xcbf29ce484222325);
+    }
+
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+
+    /// Of a list, so the same values split differently between two lists don't hash the same
+    fn count(&mut self, count: usize) {
+        self.bytes(&(count as u64).to_le_bytes());
+    }
+
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+
+    fn vec3(&mut self, value: Vec3) {
+        [value.x, value.y, value.z].into_iter().for_each(|value| self.f32(value));
+    }
+
+
+    fn option_u32(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => {
+                self.u32(1);
+                self.u32(value);
+            }
+            None => self.u32(0),
+        }
+    }
+
+
+    fn mat4(&mut self, value: &Mat4) {
+        value.columns.iter().flatten().for_each(|&value| self.f32(value));
+    }
+
+
+    fn camera(&mut self, camera: &Camera) {
+        self.vec3(camera.position);
+        self.vec3(camera.look_at);
+        self.vec3(camera.up);
+
+        match camera.projection {
+            Projection::Perspective => self.u32(0),
+            Projection::Orthographic { height } => {
+                self.u32(1);
+                self.f32(height);
+            }
+            Projection::Fisheye { fov } => {
+                self.u32(2);
+                self.f32(fov);
+            }
+            Projection::Equirectangular { ipd } => {
+                self.u32(3);
+                self.option_u32(ipd.map(f32::to_bits));
+            }
+        }
+
+        self.f32(camera.fov);
+        self.f32(camera.sensor_height);
+
+        match camera.lens {
+            Some(lens) => {
+                self.u32(1);
+                self.f32(lens.f_stop);
+                self.f32(lens.focus_distance);
+                self.u32(lens.blades);
+                self.f32(lens.blade_rotation);
+            }
+            None => self.u32(0),
+        }
+    }
+
+
+    fn triangle(&mut self, triangle: &Triangle) {
+        triangle.vertices.into_iter().chain(triangle.normals).for_each(|value| self.vec3(value));
+        triangle.uvs.iter().flatten().for_each(|&value| self.f32(value));
+        self.u32(triangle.material);
+    }
+
+
+    fn instance(&mut self, instance: &Instance) {
+        self.u32(instance.mesh);
+        self.mat4(&instance.transform);
+        self.option_u32(instance.material);
+    }
+
+
+    fn material(&mut self, material: &Material) {
+        match material.bsdf {
+            Bsdf::Diffuse => self.u32(0),
+            Bsdf::Conductor(ior) => {
+                self.u32(1);
+                self.vec3(ior.eta);
+                self.vec3(ior.k);
+            }
+            Bsdf::Dielectric { ior } => {
+                self.u32(2);
+                self.f32(ior);
+            }
+            Bsdf::Principled(principled) => {
+                self.u32(3);
+                [
+                    principled.specular,
+                    principled.anisotropy,
+                    principled.sheen,
+                    principled.sheen_tint,
+                    principled.clearcoat,
+                    principled.clearcoat_roughness,
+                    principled.transmission,
+                    principled.ior,
+                    principled.subsurface,
+                ].into_iter().for_each(|value| self.f32(value));
+            }
+        }
+
+        self.vec3(material.albedo);
+        self.option_u32(material.albedo_texture);
+        self.vec3(material.emission);
+        self.option_u32(material.emission_texture);
+        self.f32(material.metallic);
+        self.f32(material.roughness);
+        self.option_u32(material.metallic_roughness_texture);
+    }
+
+
+    fn texture(&mut self, texture: &Texture) {
+        self.u32(texture.width);
+        self.u32(texture.height);
+        self.count(texture.pixels.len());
+        self.bytes(&texture.pixels);
+    }
+
+
+    fn light(&mut self, light: &Light) {
+        match *light {
+            Light::Point { position, intensity } => {
+                self.u32(0);
+                self.vec3(position);
+                self.vec3(intensity);
+            }
+            Light::Spot { position, direction, intensity, inner_angle, outer_angle, falloff } => {
+                self.u32(1);
+                self.vec3(position);
+                self.vec3(direction);
+                self.vec3(intensity);
+                self.f32(inner_angle);
+                self.f32(outer_angle);
+                self.f32(falloff);
+            }
+            Light::Directional { direction, irradiance, angular_diameter } => {
+                self.u32(2);
+                self.vec3(direction);
+                self.vec3(irradiance);
+                self.f32(angular_diameter);
+            }
+        }
+    }
+
+
+    fn environment(&mut self, environment: &EnvironmentMap) {
+        self.u32(environment.width);
+        self.u32(environment.height);
+        self.count(environment.pixels.len());
+        environment.pixels.iter().for_each(|&value| self.f32(value));
+        self.f32(environment.rotation);
+        self.f32(environment.intensity);
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn changes_with_the_scene_and_settings() {
+        let mut scene = Scene::cornell_box();
+        scene.lights.push(Light::Point { position: Vec3::Y, intensity: Vec3::ONE });
+        let settings = RenderSettings::default();
+
+        let original = fingerprint(&scene, &settings);
+        assert_eq!(fingerprint(&scene.clone(), &settings.clone()), original);
+
+        let mut changed = scene.clone();
+        changed.materials[0].albedo.x += 0.01;
+        assert_ne!(fingerprint(&changed, &settings), original);
+
+        let mut changed = scene.clone();
+        changed.lights[0] = Light::Point { position: Vec3::Y, intensity: Vec3::splat(2.0) };
+        assert_ne!(fingerprint(&changed, &settings), original);
+
+        let mut changed = scene.clone();
+        changed.sun = Some(Light::Directional { direction: Vec3::new(0.0, -1.0, 0.0), irradiance: Vec3::ONE, angular_diameter: 0.01 });
+        assert_ne!(fingerprint(&changed, &settings), original);
+
+        let mut changed = scene.clone();
+        changed.materials[0].bsdf = Bsdf::Dielectric { ior: 1.5 };
+        assert_ne!(fingerprint(&changed, &settings), original);
+
+        let more_bounces = RenderSettings { max_bounces: settings.max_bounces + 1, ..settings.clone() };
+        assert_ne!(fingerprint(&scene, &more_bounces), original);
+
+        // the seed is checked on its own
+        let other_seed = RenderSettings { seed: settings.seed + 1, ..settings.clone() };
+        assert_eq!(fingerprint(&scene, &other_seed), original);
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::Arc;
+
+use vulkano::acceleration_structure::{
+    AccelerationStructure, AccelerationStructureGeometries, AccelerationStructureGeometryInstancesData,
+    AccelerationStructureGeometryInstancesDataType, AccelerationStructureGeometryTrianglesData,
+    AccelerationStructureInstance, AccelerationStructureType, GeometryFlags,
+};
+use vulkano::buffer::{BufferContents, BufferUsage, Subbuffer};
+use vulkano::format::Format;
+use vulkano::Packed24_8;
+
+use crate::bvh::{Bvh, BvhNode};
+use crate::gpu::{GPU, GPUError};
+use crate::math::{Mat4, Vec3};
+use crate::scene::{Instance, Scene, Triangle};
+
+
+
+
+// must match the Triangle struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct GPUTriangle {
+    v0: [f32; 4],
+    v1: [f32; 4],
+    v2: [f32; 4],
+    n0: [f32; 4],
+    n1: [f32; 4],
+    n2: [f32; 4],
+    uv01: [f32; 4],
+    uv2: [f32; 2],
+    material: u32,
+    // std430 rounds the struct up to the alignment of a vec4
+    _padding: u32,
+}
+
+
+// must match the Instance struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct GPUInstance {
+    /// Rows of the 3x4 object to world transform
+    object_to_world: [[f32; 4]; 3],
+    world_to_object: [[f32; 4]; 3],
+    /// First triangle of the mesh in the triangle buffer
+    triangle_offset: u32,
+    /// Root of the mesh's BVH in the node buffer, unused by the hardware backend
+    node_offset: u32,
+    /// Replaces the triangles' materials, -1 keeps them
+    material: i32,
+    /// What the ObjectId AOV shows, see flatten
+    object: u32,
+}
+
+
+/// What the path tracer traces against, the bindings of its triangle, instance and acceleration buffers
+pub(super) struct Geometry {
+    pub triangles: Subbuffer<[GPUTriangle]>,
+    pub instances: Subbuffer<[GPUInstance]>,
+    pub acceleration: Acceleration,
+    /// Every emissive triangle of every instance, for the light list
+    pub emitters: Vec<Emitter>,
+    /// How many triangles the mesh of each entry of the instance buffer has
+    pub instance_triangles: Vec<u32>,
+}
+
+
+/// An emissive triangle of one instance
+pub(super) struct Emitter {
+    /// Index into the instance buffer
+    pub instance: u32,
+    /// Of the triangle in the triangle buffer, counted from its instance's triangle_offset
+    pub triangle: u32,
+    /// In world space
+    pub vertices: [Vec3; 3],
+    pub uvs: [[f32; 2]; 3],
+    pub material: u32,
+}
+
+
+pub(super) enum Acceleration {
+    /// Top level BVH first, so its root is node 0, followed by every mesh's bottom level
+    Software(Subbuffer<[BvhNode]>),
+    Hardware(HardwareStructures),
+}
+
+
+pub(super) struct HardwareStructures {
+    pub top_level: Arc<AccelerationStructure>,
+    // the top level only refers to these by device address, so they have to be kept alive by hand
+    _bottom_levels: Vec<Arc<AccelerationStructure>>,
+}
+
+
+
+
+/// The scene's meshes with its loose world space triangles as one more mesh in front,
+/// and every instance that has something to trace, pointing into that list.
+/// Instances come with their object ID, 0 for the loose triangles and one more than their index in the scene otherwise
+fn flatten(scene: &Scene) -> (Vec<&[Triangle]>, Vec<(u32, Instance)>) {
+    let mut meshes = vec![scene.triangles.as_slice()];
+    meshes.extend(scene.meshes.iter().map(|mesh| mesh.triangles.as_slice()));
+
+    let mut instances = Vec::with_capacity(scene.instances.len() + 1);
+
+    if !scene.triangles.is_empty() {
+        instances.push((0, Instance { mesh: 0, transform: Mat4::IDENTITY, material: None }));
+    }
+
+    instances.extend(scene.instances.iter().enumerate()
+        .filter(|(_, instance)| !scene.meshes[instance.mesh as usize].triangles.is_empty())
+        .map(|(index, instance)| (index as u32 + 1, Instance { mesh: instance.mesh + 1, ..instance.clone() })));
+
+    return (meshes, instances);
+}
+
+
+/// The emissive triangles of instances in the order of the instance buffer. order turns a triangle's
+/// place in its mesh's part of the triangle buffer into its index in the mesh
+fn find_emitters(scene: &Scene, meshes: &[&[Triangle]], instances: &[&Instance], order: impl Fn(usize, usize) -> usize) -> Vec<Emitter> {
+    let mut emitters = Vec::new();
+
+    for (index, instance) in instances.iter().enumerate() {
+        let mesh = meshes[instance.mesh as usize];
+
+        for position in 0..mesh.len() {
+            let triangle = &mesh[order(instance.mesh as usize, position)];
+            let material = instance.material.unwrap_or(triangle.material);
+
+            if scene.materials[material as usize].emission != Vec3::ZERO {
+                emitters.push(Emitter {
+                    instance: index as u32,
+                    triangle: position as u32,
+                    vertices: triangle.vertices.map(|vertex| instance.transform.transform_point(vertex)),
+                    uvs: triangle.uvs,
+                    material: material
+                });
+            }
+        }
+    }
+
+    return emitters;
+}
+
+
+fn gpu_triangle(triangle: &Triangle) -> GPUTriangle {
+    return GPUTriangle {
+        v0: triangle.vertices[0].extend(0.0),
+        v1: triangle.vertices[1].extend(0.0),
+        v2: triangle.vertices[2].extend(0.0),
+        n0: triangle.normals[0].extend(0.0),
+        n1: triangle.normals[1].extend(0.0),
+        n2: triangle.normals[2].extend(0.0),
+        uv01: [triangle.uvs[0][0], triangle.uvs[0][1], triangle.uvs[1][0], triangle.uvs[1][1]],
+        uv2: triangle.uvs[2],
+        material: triangle.material,
+        _padding: 0
+    };
+}
+
+
+fn gpu_instance(object: u32, instance: &Instance, triangle_offset: u32, node_offset: u32) -> GPUInstance {
+    let world_to_object = instance.transform.affine_inverse().expect("instance transforms are checked before rendering");
+
+    return GPUInstance {
+        object_to_world: instance.transform.rows_3x4(),
+        world_to_object: world_to_object.rows_3x4(),
+        triangle_offset: triangle_offset,
+        node_offset: node_offset,
+        material: instance.material.map_or(-1, |material| material as i32),
+        object: object
+    };
+}
+
+
+
+
+/////////// Software
+
+/// A BVH per mesh plus one over the instances, all in one node buffer.
+/// Every mesh is stored once however many instances it has
+pub(super) fn upload_software(gpu: &GPU, scene: &Scene) -> Result<Geometry, GPUError> {
+    let (meshes, instances) = flatten(scene);
+
+    let bottom_levels: Vec<Bvh> = meshes.iter().map(|triangles| Bvh::over_triangles(triangles)).collect();
+    let top_level = Bvh::build(instances.iter()
+        .map(|(_, instance)| bottom_levels[instance.mesh as usize].bounds().transform(&instance.transform))
+        .collect());
+
+    // top level leaves refer to instances, which are uploaded in its order
+    let mut nodes = top_level.nodes;
+    let mut triangles = Vec::new();
+    let mut offsets = Vec::with_capacity(meshes.len());
+
+    for (mesh, bvh) in meshes.iter().zip(&bottom_levels) {
+        let triangle_offset = triangles.len() as u32;
+        let node_offset = nodes.len() as u32;
+
+        // make the bottom level's triangle and child indices point into the shared buffers
+        triangles.extend(bvh.order.iter().map(|&index| gpu_triangle(&mesh[index as usize])));
+        nodes.extend(bvh.nodes.iter().map(|node| BvhNode {
+            left_first: node.left_first + if node.count > 0 { triangle_offset } else { node_offset },
+            ..*node
+        }));
+
+        offsets.push((triangle_offset, node_offset));
+    }
+
+    let gpu_instances = top_level.order.iter().map(|&index| {
+        let (object, instance) = &instances[index as usize];
+        let (triangle_offset, node_offset) = offsets[instance.mesh as usize];
+        return gpu_instance(*object, instance, triangle_offset, node_offset);
+    });
+
+    let ordered: Vec<&Instance> = top_level.order.iter().map(|&index| &instances[index as usize].1).collect();
+
+    return Ok(Geometry {
+        instances: gpu.upload_buffer(gpu_instances, BufferUsage::STORAGE_BUFFER)?,
+        triangles: gpu.upload_buffer(triangles, BufferUsage::STORAGE_BUFFER)?,
+        acceleration: Acceleration::Software(gpu.upload_buffer(nodes, BufferUsage::STORAGE_BUFFER)?),
+        emitters: find_emitters(scene, &meshes, &ordered, |mesh, position| bottom_levels[mesh].order[position] as usize),
+        instance_triangles: ordered.iter().map(|instance| meshes[instance.mesh as usize].len() as u32).collect()
+    });
+}
+
+
+
+
+/////////// Hardware
+
+/// A bottom level acceleration structure per mesh and a top level one over the instances.
+/// Primitive indices are relative to the mesh, the instance's custom index says which instance was hit
+pub(super) fn upload_hardware(gpu: &GPU, scene: &Scene) -> Result<Geometry, GPUError> {
+    let (meshes, instances) = flatten(scene);
+
+    let mut triangles = Vec::new();
+    let mut triangle_offsets = Vec::with_capacity(meshes.len());
+    let mut bottom_levels = Vec::with_capacity(meshes.len());
+
+    for mesh in &meshes {
+        triangle_offsets.push(triangles.len() as u32);
+        triangles.extend(mesh.iter().map(gpu_triangle));
+
+        // empty meshes have no instances, so they don't need a structure either
+        bottom_levels.push(if mesh.is_empty() { None } else { Some(build_bottom_level(gpu, mesh)?) });
+    }
+
+    let hardware_instances = instances.iter().enumerate().map(|(index, (_, instance))| {
+        let bottom_level = bottom_levels[instance.mesh as usize].as_ref().expect("instances of empty meshes are left out");
+
+        return AccelerationStructureInstance {
+            transform: instance.transform.rows_3x4(),
+            instance_custom_index_and_mask: Packed24_8::new(index as u32, 0xFF),
+            acceleration_structure_reference: bottom_level.device_address().get(),
+            ..Default::default()
+        };
+    }).collect::<Vec<_>>();
+
+    let instance_buffer = gpu.upload_buffer(
+        hardware_instances,
+        BufferUsage::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY | BufferUsage::SHADER_DEVICE_ADDRESS
+    )?;
+
+    let top_level = gpu.build_acceleration_structure(
+        AccelerationStructureGeometries::Instances(AccelerationStructureGeometryInstancesData::new(
+            AccelerationStructureGeometryInstancesDataType::Values(Some(instance_buffer))
+        )),
+        instances.len() as u32,
+        AccelerationStructureType::TopLevel
+    )?;
+
+    let gpu_instances = instances.iter().map(|(object, instance)| gpu_instance(*object, instance, triangle_offsets[instance.mesh as usize], 0));
+
+    let ordered: Vec<&Instance> = instances.iter().map(|(_, instance)| instance).collect();
+
+    return Ok(Geometry {
+        instances: gpu.upload_buffer(gpu_instances, BufferUsage::STORAGE_BUFFER)?,
+        triangles: gpu.upload_buffer(triangles, BufferUsage::STORAGE_BUFFER)?,
+        acceleration: Acceleration::Hardware(HardwareStructures {
+            top_level: top_level,
+            _bottom_levels: bottom_levels.into_iter().flatten().collect()
+        }),
+        // the triangles are in mesh order
+        emitters: find_emitters(scene, &meshes, &ordered, |_, position| position),
+        instance_triangles: ordered.iter().map(|instance| meshes[instance.mesh as usize].len() as u32).collect()
+    });
+}
+
+
+fn build_bottom_level(gpu: &GPU, triangles: &[Triangle]) -> Result<Arc<AccelerationStructure>, GPUError> {
+    // three vertices per triangle in mesh order, so primitive indices match the triangle buffer
+    let positions = gpu.upload_buffer(
+        triangles.iter().flat_map(|triangle| triangle.vertices.map(<[f32; 3]>::from)).collect::<Vec<[f32; 3]>>(),
+        BufferUsage::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY | BufferUsage::SHADER_DEVICE_ADDRESS
+    )?;
+
+    let vertex_count = positions.len() as u32;
+    let geometry = AccelerationStructureGeometryTrianglesData {
+        flags: GeometryFlags::OPAQUE,
+        vertex_data: Some(positions.into_bytes()),
+        vertex_stride: size_of::<[f32; 3]>() as u32,
+        max_vertex: vertex_count - 1,
+        ..AccelerationStructureGeometryTrianglesData::new(Format::R32G32B32_SFLOAT)
+    };
+
+    return gpu.build_acceleration_structure(
+        AccelerationStructureGeometries::Triangles(vec![geometry]),
+        triangles.len() as u32,
+        AccelerationStructureType::BottomLevel
+    );
+}
@@ -0,0 +1,333 @@
+//! A BVH over the lights that have a position, after Conty Estevez and Kulla's "Importance Sampling of Many Lights
+//! with Adaptive Tree Splitting" the way pbrt-v4 does it. Every node bounds where its lights are, how much they give
+//! off and which way they face, so the shader can walk down towards the lights that matter most to a point.
+
+use std::f32::consts::PI;
+
+use vulkano::buffer::BufferContents;
+
+use crate::bvh::Aabb;
+use crate::math::Vec3;
+
+
+
+
+/// Where a light is, how strong and which way it shines
+#[derive(Clone, Copy)]
+pub(super) struct LightBounds {
+    pub bounds: Aabb,
+    /// Only ever compared with other lights
+    pub power: f32,
+    /// Middle of the directions the lights face
+    pub axis: Vec3,
+    /// Cosine of the angle around axis that holds every direction the lights face
+    pub cos_theta_o: f32,
+    /// Cosine of how far past those directions light still leaves
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+
+impl LightBounds {
+    pub(super) fn point(position: Vec3, power: f32) -> Self {
+        return Self {
+            bounds: Aabb { min: position, max: position },
+            power: power,
+            axis: Vec3::Y,
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+            two_sided: false
+        };
+    }
+
+
+    pub(super) fn spot(position: Vec3, direction: Vec3, cos_inner: f32, cos_outer: f32, power: f32) -> Self {
+        return Self {
+            bounds: Aabb { min: position, max: position },
+            power: power,
+            axis: direction,
+            cos_theta_o: cos_inner,
+            cos_theta_e: (cos_outer.acos() - cos_inner.acos()).cos(),
+            two_sided: false
+        };
+    }
+
+
+    /// Of a triangle emitting from both sides
+    pub(super) fn triangle(vertices: [Vec3; 3], power: f32) -> Self {
+        let mut bounds = Aabb::EMPTY;
+        for vertex in vertices {
+            bounds.grow(vertex);
+        }
+
+        return Self {
+            bounds: bounds,
+            power: power,
+            axis: (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]).normalize(),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: true
+        };
+    }
+
+
+    fn union(self, other: LightBounds) -> LightBounds {
+        let (axis, cos_theta_o) = union_cones(self.axis, self.cos_theta_o, other.axis, other.cos_theta_o);
+
+        return LightBounds {
+            bounds: self.bounds.union(other.bounds),
+            power: self.power + other.power,
+            axis: axis,
+            cos_theta_o: cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided
+        };
+    }
+}
+
+
+/// The smallest cone holding two cones of directions, given by their axes and the cosines of their spread
+fn union_cones(a: Vec3, cos_a: f32, b: Vec3, cos_b: f32) -> (Vec3, f32) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = a.dot(b).clamp(-1.0, 1.0).acos();
+
+    // one already holds the other
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (b, cos_b);
+    }
+
+    let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+    let rotation_axis = a.cross(b);
+
+    if theta_o >= PI || rotation_axis.length() == 0.0 {
+        return (a, -1.0);
+    }
+
+    // turn a towards b until the cone's edge reaches a's
+    let k = rotation_axis.normalize();
+    let theta_r = theta_o - theta_a;
+    let axis = a * theta_r.cos() + k.cross(a) * theta_r.sin() + k * (k.dot(a) * (1.0 - theta_r.cos()));
+
+    return (axis.normalize(), theta_o.cos());
+}
+
+
+
+
+// must match the LightNode struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct GPULightNode {
+    min: [f32; 3],
+    power: f32,
+    max: [f32; 3],
+    cos_theta_o: f32,
+    axis: [f32; 3],
+    cos_theta_e: f32,
+    /// The left child of an interior node, the right child always follows it, or a leaf's light
+    child_or_light: u32,
+    /// Nonzero for leaves
+    leaf: u32,
+    two_sided: u32,
+    _padding: u32,
+}
+
+
+impl GPULightNode {
+    fn new(bounds: LightBounds) -> Self {
+        return Self {
+            min: bounds.bounds.min.into(),
+            power: bounds.power,
+            max: bounds.bounds.max.into(),
+            cos_theta_o: bounds.cos_theta_o,
+            axis: bounds.axis.into(),
+            cos_theta_e: bounds.cos_theta_e,
+            child_or_light: 0,
+            leaf: 0,
+            two_sided: bounds.two_sided as u32,
+            _padding: 0
+        };
+    }
+
+
+    /// For a scene without lights in the tree, whose buffer still has to be bound
+    pub(super) fn placeholder() -> Self {
+        return Self::new(LightBounds::point(Vec3::ZERO, 0.0));
+    }
+}
+
+
+pub(super) struct LightTree {
+    /// The root is the first node, every leaf holds one light
+    pub nodes: Vec<GPULightNode>,
+    /// The way from the root to every light's leaf, bit n set for taking the right child at depth n
+    pub trails: Vec<u32>,
+}
+
+
+impl LightTree {
+    /// Over lights that start at index first in the light buffer
+    pub(super) fn build(lights: &[LightBounds], first: u32) -> Self {
+        let mut builder = Builder {
+            lights: lights,
+            centroids: lights.iter().map(|light| light.bounds.center()).collect(),
+            first: first,
+            nodes: Vec::with_capacity((2 * lights.len()).saturating_sub(1)),
+            trails: vec![0; lights.len()]
+        };
+
+        if !lights.is_empty() {
+            let mut indices: Vec<u32> = (0..lights.len() as u32).collect();
+            builder.nodes.push(GPULightNode::placeholder());
+            builder.subdivide(0, &mut indices, 0, 0);
+        }
+
+        return LightTree {
+            nodes: builder.nodes,
+            trails: builder.trails
+        };
+    }
+}
+
+
+
+
+/////////// Building
+
+struct Builder<'a> {
+    lights: &'a [LightBounds],
+    centroids: Vec<Vec3>,
+    first: u32,
+    nodes: Vec<GPULightNode>,
+    trails: Vec<u32>,
+}
+
+
+impl Builder<'_> {
+    /// Splits in half at the median centroid, which keeps every trail within 32 bits
+    fn subdivide(&mut self, node: usize, indices: &mut [u32], depth: u32, trail: u32) {
+        let bounds = indices[1..].iter().fold(self.lights[indices[0] as usize], |bounds, &index| bounds.union(self.lights[index as usize]));
+        self.nodes[node] = GPULightNode::new(bounds);
+
+        if let [light] = indices {
+            self.nodes[node].child_or_light = self.first + *light;
+            self.nodes[node].leaf = 1;
+            self.trails[*light as usize] = trail;
+            return;
+        }
+
+        let centroid_bounds = indices.iter().fold(Aabb::EMPTY, |mut bounds, &index| {
+            bounds.grow(self.centroids[index as usize]);
+            return bounds;
+        });
+
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+
+        let middle = indices.len() / 2;
+        indices.select_nth_unstable_by(middle, |a, b| self.centroids[*a as usize][axis].total_cmp(&self.centroids[*b as usize][axis]));
+
+        let left = self.nodes.len();
+        self.nodes.push(self.nodes[node]);
+        self.nodes.push(self.nodes[node]);
+        self.nodes[node].child_or_light = left as u32;
+
+        let (left_indices, right_indices) = indices.split_at_mut(middle);
+        self.subdivide(left, left_indices, depth + 1, trail);
+        self.subdivide(left + 1, right_indices, depth + 1, trail | 1 << depth);
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// A few hundred lights of every kind, scattered by a fixed hash
+    fn lights(count: u32) -> Vec<LightBounds> {
+        let random = |i: u32, salt: u32| (i.wrapping_mul(2654435761) ^ salt.wrapping_mul(40503)).wrapping_mul(2246822519) as f32 / u32::MAX as f32;
+        let point = |i: u32| Vec3::new(random(i, 1), random(i, 2), random(i, 3)) * 10.0 - Vec3::splat(5.0);
+
+        return (0..count).map(|i| match i % 3 {
+            0 => LightBounds::point(point(i), 1.0 + random(i, 4)),
+            1 => LightBounds::spot(point(i), Vec3::new(0.0, -1.0, 0.0), 0.9, 0.5, 2.0 * random(i, 5)),
+            _ => LightBounds::triangle([point(i), point(i) + Vec3::new(0.3, 0.0, 0.0), point(i) + Vec3::new(0.0, 0.0, 0.3)], random(i, 6)),
+        }).collect();
+    }
+
+
+    #[test]
+    fn trails_lead_to_their_own_leaves() {
+        for count in [1, 2, 3, 7, 300] {
+            let first = 2;
+            let tree = LightTree::build(&lights(count), first);
+            assert_eq!(tree.nodes.len(), 2 * count as usize - 1);
+
+            for (light, &trail) in tree.trails.iter().enumerate() {
+                let mut node = 0;
+                let mut depth = 0;
+
+                while tree.nodes[node].leaf == 0 {
+                    node = tree.nodes[node].child_or_light as usize + (trail >> depth & 1) as usize;
+                    depth += 1;
+                }
+
+                assert_eq!(tree.nodes[node].child_or_light, first + light as u32);
+                assert_eq!(trail >> depth, 0, "light {light}'s trail goes on past its leaf");
+            }
+        }
+    }
+
+
+    #[test]
+    fn builds_a_small_tree_by_hand() {
+        // two spots on the left facing +z and +x, two points on the right
+        let cos_30 = 30f32.to_radians().cos();
+        let lights = [
+            LightBounds::spot(Vec3::ZERO, Vec3::new(0.0, 0.0, 1.0), 1.0, cos_30, 1.0),
+            LightBounds::spot(Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 1.0, cos_30, 2.0),
+            LightBounds::point(Vec3::new(2.0, 0.0, 0.0), 3.0),
+            LightBounds::point(Vec3::new(3.0, 1.0, 0.0), 4.0),
+        ];
+
+        let tree = LightTree::build(&lights, 5);
+
+        // the root splits along x, then each half again
+        assert_eq!(tree.trails, [0b00, 0b10, 0b01, 0b11]);
+        assert_eq!(tree.nodes.iter().map(|node| (node.child_or_light, node.leaf)).collect::<Vec<_>>(), [
+            (1, 0), (3, 0), (5, 0), (5, 1), (6, 1), (7, 1), (8, 1),
+        ]);
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        let bounds = |node: usize| (tree.nodes[node].min, tree.nodes[node].max, tree.nodes[node].power);
+        assert_eq!(bounds(0), ([0.0, 0.0, 0.0], [3.0, 1.0, 0.0], 10.0));
+        assert_eq!(bounds(1), ([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], 3.0));
+        assert_eq!(bounds(2), ([2.0, 0.0, 0.0], [3.0, 1.0, 0.0], 7.0));
+        assert_eq!(bounds(6), ([3.0, 1.0, 0.0], [3.0, 1.0, 0.0], 4.0));
+
+        // a spot's cone is its inner angle, and light still leaves up to the outer one
+        let spot = &tree.nodes[3];
+        assert_eq!((spot.axis, spot.cos_theta_o), ([0.0, 0.0, 1.0], 1.0));
+        assert!(close(spot.cos_theta_e, cos_30));
+
+        // the two spots together face anywhere within 45 degrees of the direction between theirs
+        let spots = &tree.nodes[1];
+        let half = 0.5f32.sqrt();
+        assert!(close(spots.axis[0], half) && close(spots.axis[1], 0.0) && close(spots.axis[2], half), "{:?}", spots.axis);
+        assert!(close(spots.cos_theta_o, half));
+        assert!(close(spots.cos_theta_e, cos_30));
+
+        // points shine every way, which swallows the spots' cones
+        for node in [0, 2] {
+            assert_eq!((tree.nodes[node].axis, tree.nodes[node].cos_theta_o, tree.nodes[node].cos_theta_e), ([0.0, 1.0, 0.0], -1.0, 0.0));
+        }
+        assert!(tree.nodes.iter().all(|node| node.two_sided == 0));
+    }
+}
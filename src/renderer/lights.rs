@@ -0,0 +1,283 @@
+use std::f32::consts::PI;
+
+use vulkano::buffer::BufferContents;
+
+use crate::bvh::Aabb;
+use crate::math::Vec3;
+use crate::scene::{Light, Scene};
+
+use super::environment::Environment;
+use super::geometry::Emitter;
+use super::light_tree::{LightBounds, LightTree};
+
+
+
+
+// must match the LIGHT_ constants in shaders::path_tracer
+const LIGHT_POINT: u32 = 0;
+const LIGHT_SPOT: u32 = 1;
+const LIGHT_DIRECTIONAL: u32 = 2;
+const LIGHT_TRIANGLE: u32 = 3;
+const LIGHT_ENVIRONMENT: u32 = 4;
+
+
+// must match the Light struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+pub(super) struct GPULight {
+    /// Of point and spot lights, or a triangle's first vertex
+    position: [f32; 4],
+    /// The way spot and directional lights shine, or a triangle's edge to its second vertex
+    direction: [f32; 4],
+    /// A triangle's edge to its third vertex
+    edge: [f32; 4],
+    /// Intensity of point and spot lights or irradiance of directional ones, triangles use their material's emission
+    intensity: [f32; 4],
+    /// A triangle's texture coordinates, for emission textures
+    uv01: [f32; 4],
+    uv2: [f32; 2],
+    /// The LIGHT_ constant
+    kind: u32,
+    /// A triangle's material
+    material: u32,
+    /// Cosines of a spot light's cones
+    cos_inner: f32,
+    cos_outer: f32,
+    falloff: f32,
+    /// Cosine of half a directional light's angular diameter, 1 for a hard one
+    cos_radius: f32,
+    /// Chance of picking this light by power, and of picking it or any light before it
+    probability: f32,
+    cdf: f32,
+    /// Its way down the light tree, see LightTree::trails
+    trail: u32,
+    _padding: u32,
+}
+
+
+impl GPULight {
+    fn empty(kind: u32) -> Self {
+        return Self {
+            position: [0.0; 4],
+            direction: [0.0; 4],
+            edge: [0.0; 4],
+            intensity: [0.0; 4],
+            uv01: [0.0; 4],
+            uv2: [0.0; 2],
+            kind: kind,
+            material: 0,
+            cos_inner: 1.0,
+            cos_outer: 1.0,
+            falloff: 1.0,
+            cos_radius: 1.0,
+            probability: 0.0,
+            cdf: 0.0,
+            trail: 0,
+            _padding: 0
+        };
+    }
+
+
+    /// A black point light, for a scene without lights whose buffer still has to be bound
+    pub(super) fn placeholder() -> Self {
+        return Self::empty(LIGHT_POINT);
+    }
+}
+
+
+
+
+/// Every light in the scene that gives off anything, in the order the shader wants them
+pub(super) struct Lights {
+    /// The environment map, then directional lights, then point and spot lights, then the emissive triangles
+    pub lights: Vec<GPULight>,
+    /// The environment and directional lights at the front, which shine from outside the scene
+    /// and so are left out of the tree
+    pub infinite: u32,
+    /// Over the rest
+    pub tree: LightTree,
+    /// For every entry of the instance buffer, where the light indices of its triangles start in here, or -1
+    /// when none of them emit. Then those indices, -1 for triangles that aren't lights
+    pub emitter_lights: Vec<i32>,
+}
+
+
+struct Candidate<'a> {
+    light: GPULight,
+    power: f32,
+    /// None for lights outside the tree
+    bounds: Option<LightBounds>,
+    emitter: Option<&'a Emitter>,
+}
+
+
+/// The shader picks lights either in proportion to their power, or by walking the tree.
+/// instance_triangles is the number of triangles of every instance in the instance buffer
+pub(super) fn gather(scene: &Scene, emitters: &[Emitter], instance_triangles: &[u32], environment: Option<&Environment>) -> Lights {
+    let mut candidates = Vec::new();
+
+    // directional lights and the environment shine on the whole scene, so their power depends on its size
+    let radius = bounds(scene).map_or(0.0, |bounds| (bounds.max - bounds.min).length() * 0.5);
+
+    // the shader samples it from its own CDFs, this only decides how often
+    if let Some(environment) = environment {
+        candidates.push(Candidate {
+            light: GPULight::empty(LIGHT_ENVIRONMENT),
+            power: 4.0 * PI * PI * radius * radius * environment.average_luminance,
+            bounds: None,
+            emitter: None
+        });
+    }
+
+    for light in scene.all_lights() {
+        if let Light::Directional { direction, irradiance, angular_diameter } = *light {
+            let mut gpu_light = GPULight::empty(LIGHT_DIRECTIONAL);
+            gpu_light.direction = direction.normalize().extend(0.0);
+            gpu_light.intensity = irradiance.extend(0.0);
+            gpu_light.cos_radius = (angular_diameter * 0.5).cos();
+
+            candidates.push(Candidate {
+                light: gpu_light,
+                power: PI * radius * radius * luminance(irradiance),
+                bounds: None,
+                emitter: None
+            });
+        }
+    }
+
+    for light in scene.all_lights() {
+        let (gpu_light, power, light_bounds) = match *light {
+            Light::Point { position, intensity } => {
+                let mut gpu_light = GPULight::empty(LIGHT_POINT);
+                gpu_light.position = position.extend(1.0);
+                gpu_light.intensity = intensity.extend(0.0);
+
+                let power = 4.0 * PI * luminance(intensity);
+                (gpu_light, power, LightBounds::point(position, power))
+            }
+            Light::Spot { position, direction, intensity, inner_angle, outer_angle, falloff } => {
+                let mut gpu_light = GPULight::empty(LIGHT_SPOT);
+                gpu_light.position = position.extend(1.0);
+                gpu_light.direction = direction.normalize().extend(0.0);
+                gpu_light.intensity = intensity.extend(0.0);
+                gpu_light.cos_inner = inner_angle.cos();
+                gpu_light.cos_outer = outer_angle.cos();
+                gpu_light.falloff = falloff;
+
+                // the solid angle of a cone halfway between the two
+                let cos_middle = (gpu_light.cos_inner + gpu_light.cos_outer) * 0.5;
+                let power = 2.0 * PI * (1.0 - cos_middle) * luminance(intensity);
+                (gpu_light, power, LightBounds::spot(position, direction.normalize(), gpu_light.cos_inner, gpu_light.cos_outer, power))
+            }
+            Light::Directional { .. } => continue,
+        };
+
+        candidates.push(Candidate {
+            light: gpu_light,
+            power: power,
+            bounds: Some(light_bounds),
+            emitter: None
+        });
+    }
+
+    for emitter in emitters {
+        let [v0, v1, v2] = emitter.vertices;
+        let area = (v1 - v0).cross(v2 - v0).length() * 0.5;
+        if area == 0.0 {
+            continue;
+        }
+
+        let mut gpu_light = GPULight::empty(LIGHT_TRIANGLE);
+        gpu_light.position = v0.extend(1.0);
+        gpu_light.direction = (v1 - v0).extend(0.0);
+        gpu_light.edge = (v2 - v0).extend(0.0);
+        gpu_light.uv01 = [emitter.uvs[0][0], emitter.uvs[0][1], emitter.uvs[1][0], emitter.uvs[1][1]];
+        gpu_light.uv2 = emitter.uvs[2];
+        gpu_light.material = emitter.material;
+
+        // both sides emit. Emission textures are left out, the factor alone is close enough to pick by
+        let power = 2.0 * PI * area * luminance(scene.materials[emitter.material as usize].emission);
+
+        candidates.push(Candidate {
+            light: gpu_light,
+            power: power,
+            bounds: Some(LightBounds::triangle(emitter.vertices, power)),
+            emitter: Some(emitter)
+        });
+    }
+
+    // black lights would never be picked, and the shader weighs what it hits by the chance of picking it
+    candidates.retain(|candidate| candidate.power > 0.0 && candidate.power.is_finite());
+
+    let total: f32 = candidates.iter().map(|candidate| candidate.power).sum();
+    let infinite = candidates.iter().take_while(|candidate| candidate.bounds.is_none()).count();
+
+    let light_bounds: Vec<LightBounds> = candidates[infinite..].iter().filter_map(|candidate| candidate.bounds).collect();
+    let tree = LightTree::build(&light_bounds, infinite as u32);
+
+    let mut lights = Vec::with_capacity(candidates.len());
+    let mut emitter_lights = vec![-1; instance_triangles.len()];
+    let mut cdf = 0.0;
+
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        let mut light = candidate.light;
+        light.probability = candidate.power / total;
+        cdf += light.probability;
+        light.cdf = cdf;
+
+        if index >= infinite {
+            light.trail = tree.trails[index - infinite];
+        }
+
+        // instances only get a table once one of their triangles turns out to be a light
+        if let Some(emitter) = candidate.emitter {
+            let instance = emitter.instance as usize;
+            if emitter_lights[instance] < 0 {
+                let offset = emitter_lights.len();
+                emitter_lights[instance] = offset as i32;
+                emitter_lights.resize(offset + instance_triangles[instance] as usize, -1);
+            }
+
+            let offset = emitter_lights[instance] as usize;
+            emitter_lights[offset + emitter.triangle as usize] = index as i32;
+        }
+
+        lights.push(light);
+    }
+
+    // rounding mustn't leave a gap at the end for the shader to fall into
+    if let Some(last) = lights.last_mut() {
+        last.cdf = 1.0;
+    }
+
+    return Lights {
+        lights: lights,
+        infinite: infinite as u32,
+        tree: tree,
+        emitter_lights: emitter_lights
+    };
+}
+
+
+/// Of every triangle in world space, None for an empty scene
+fn bounds(scene: &Scene) -> Option<Aabb> {
+    let mut bounds = scene.triangles.iter().fold(Aabb::EMPTY, |bounds, triangle| bounds.union(Aabb::triangle(triangle)));
+
+    let mesh_bounds: Vec<Aabb> = scene.meshes.iter()
+        .map(|mesh| mesh.triangles.iter().fold(Aabb::EMPTY, |bounds, triangle| bounds.union(Aabb::triangle(triangle))))
+        .collect();
+
+    for instance in &scene.instances {
+        let mesh = mesh_bounds[instance.mesh as usize];
+        if mesh.min.x <= mesh.max.x {
+            bounds = bounds.union(mesh.transform(&instance.transform));
+        }
+    }
+
+    return if bounds.min.x <= bounds.max.x { Some(bounds) } else { None };
+}
+
+
+pub(super) fn luminance(color: Vec3) -> f32 {
+    return 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+}
@@ -0,0 +1,776 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use image::RgbaImage;
+use vulkano::buffer::{BufferContents, BufferUsage};
+use vulkano::descriptor_set::{DescriptorSet, WriteDescriptorSet};
+use vulkano::format::Format;
+use vulkano::image::view::{ImageView, ImageViewCreateInfo, ImageViewType};
+use vulkano::image::{ImageCreateInfo, ImageType, ImageUsage};
+use vulkano::pipeline::compute::ComputePipelineCreateInfo;
+use vulkano::pipeline::layout::PipelineDescriptorSetLayoutCreateInfo;
+use vulkano::pipeline::{ComputePipeline, Pipeline, PipelineLayout, PipelineShaderStageCreateInfo};
+use vulkano::shader::ShaderModule;
+
+use crate::gpu::{GPU, GPUError};
+use crate::math::Vec3;
+use crate::scene::{Bsdf, ComplexIor, Light, Principled, Projection, Scene};
+use crate::shaders;
+
+mod aov;
+mod camera;
+mod display;
+mod environment;
+mod fingerprint;
+mod geometry;
+mod light_tree;
+mod lights;
+mod progressive;
+
+use camera::GPUCamera;
+use environment::Environment;
+use geometry::Acceleration;
+
+pub use aov::Aov;
+pub use display::{DisplayTransform, ToneMapOperator};
+pub use progressive::{Accumulation, ProgressiveRender};
+
+
+
+
+/// Everything about a render that isn't part of the scene
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    /// Samples per pixel in each dispatch of a progressive render. Fewer keeps every dispatch short,
+    /// more has less overhead
+    pub samples_per_dispatch: u32,
+    /// Bounces after the primary ray
+    pub max_bounces: u32,
+    pub seed: u32,
+    /// Applied by render to turn the HDR result into the 8 bit framebuffer
+    pub display: DisplayTransform,
+    /// Extra passes to render into Accumulation::aovs, in any order
+    pub aovs: Vec<Aov>,
+    /// How lights are found, anything but the default is for debugging
+    pub light_sampling: LightSampling,
+    /// How light sampling picks which light to sample
+    pub light_selection: LightSelection,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        return Self {
+            width: 1024,
+            height: 1024,
+            samples_per_pixel: 64,
+            samples_per_dispatch: 4,
+            max_bounces: 8,
+            seed: 0,
+            display: DisplayTransform::default(),
+            aovs: Vec::new(),
+            light_sampling: LightSampling::default(),
+            light_selection: LightSelection::default()
+        };
+    }
+}
+
+
+/// How rays find the triangles they hit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceBackend {
+    /// Hardware when the device supports it, software otherwise
+    #[default]
+    Auto,
+    /// A BVH built on the CPU and walked by the compute shader, works on any device
+    Software,
+    /// Ray queries against acceleration structures built by the driver, needs GPU::ray_query
+    Hardware,
+}
+
+
+/// How paths find lights. All three converge to the same image, just at different speeds, which makes
+/// the other two a check on the default. Point, spot and hard directional lights can't be hit by a ray,
+/// so they are always found by light sampling
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightSampling {
+    /// Light sampling and BSDF sampling weighed against each other by the power heuristic
+    #[default]
+    Mis,
+    /// Only by bouncing into them
+    Bsdf,
+    /// Only by shadow rays towards sampled lights, except after mirrors and glass where that can't work
+    Light,
+}
+
+impl LightSampling {
+    /// The SAMPLING_ constant in shaders::path_tracer
+    fn index(self) -> u32 {
+        return match self {
+            LightSampling::Mis => 0,
+            LightSampling::Bsdf => 1,
+            LightSampling::Light => 2,
+        };
+    }
+}
+
+
+/// How light sampling picks a light. Both are unbiased, they only differ in how much noise they leave
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightSelection {
+    /// In proportion to each light's power, wherever the point being lit is. Cheap, but with many
+    /// lights most shadow rays go to ones that are far away or facing elsewhere
+    Power,
+    /// By walking a BVH over the lights towards the ones that are bright, close and facing the point.
+    /// The environment and directional lights are picked as often as the tree as a whole
+    #[default]
+    Tree,
+}
+
+impl LightSelection {
+    /// The SELECTION_ constant in shaders::path_tracer
+    fn index(self) -> u32 {
+        return match self {
+            LightSelection::Power => 0,
+            LightSelection::Tree => 1,
+        };
+    }
+}
+
+
+/// A finished render in host memory, 8 bit sRGB RGBA rows from top to bottom
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn into_image(self) -> RgbaImage {
+        return RgbaImage::from_raw(self.width, self.height, self.pixels).expect("framebuffer size doesn't match its pixels");
+    }
+}
+
+
+#[derive(Debug)]
+pub enum RenderError {
+    /// The scene can't be rendered as is, e.g. it has no triangles
+    InvalidScene(String),
+    /// The settings can't be rendered, e.g. a zero sized image
+    InvalidSettings(String),
+    Gpu(GPUError),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            RenderError::InvalidScene(reason) => write!(f, "invalid scene: {reason}"),
+            RenderError::InvalidSettings(reason) => write!(f, "invalid render settings: {reason}"),
+            RenderError::Gpu(err) => write!(f, "{err}"),
+        };
+    }
+}
+
+impl Error for RenderError {}
+
+impl From<GPUError> for RenderError {
+    fn from(err: GPUError) -> Self {
+        return RenderError::Gpu(err);
+    }
+}
+
+
+
+
+// must match the Material struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+struct GPUMaterial {
+    albedo: [f32; 4],
+    emission: [f32; 4],
+    // the complex index of refraction of conductors
+    eta: [f32; 4],
+    k: [f32; 4],
+    metallic: f32,
+    roughness: f32,
+    // -1 for none
+    albedo_texture: i32,
+    emission_texture: i32,
+    metallic_roughness_texture: i32,
+    // the BSDF_ constant in the shader
+    bsdf: u32,
+    // of dielectrics and principled transmission
+    ior: f32,
+    // the rest of Principled
+    specular: f32,
+    anisotropy: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    transmission: f32,
+    subsurface: f32,
+    _padding: u32,
+}
+
+
+// must match the Texture struct in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+struct GPUTexture {
+    /// First texel in the texel buffer
+    offset: u32,
+    width: u32,
+    height: u32,
+}
+
+
+// must match the push constant block in shaders::path_tracer
+#[derive(BufferContents, Clone, Copy)]
+#[repr(C)]
+struct PushConstants {
+    background: [f32; 4],
+    width: u32,
+    height: u32,
+    /// Index of the first sample this dispatch adds, zero overwrites the accumulation
+    first_sample: u32,
+    samples: u32,
+    max_bounces: u32,
+    seed: u32,
+    /// Bit Aov::index is set for every AOV to write
+    aov_mask: u32,
+    /// Lights in the light buffer, which holds a placeholder when there are none
+    light_count: u32,
+    /// LightSampling::index
+    light_sampling: u32,
+    /// LightSelection::index
+    light_selection: u32,
+    /// The environment and directional lights at the front of the light buffer, which the light tree leaves out
+    infinite_lights: u32,
+    /// Zero without an environment map
+    environment_width: u32,
+    environment_height: u32,
+    /// In radians
+    environment_rotation: f32,
+}
+
+
+
+
+/// Path traces scenes on a GPU. The pipeline is built once, so one Renderer can render many scenes
+pub struct Renderer {
+    gpu: GPU,
+    pipeline: Arc<ComputePipeline>,
+    /// Tone maps accumulations to 8 bit, see ProgressiveRender::display
+    display_pipeline: Arc<ComputePipeline>,
+    /// Never Auto, that is resolved when the renderer is made
+    backend: TraceBackend,
+}
+
+
+impl Renderer {
+    /// Traces in hardware when the GPU can, see with_backend
+    pub fn new(gpu: GPU) -> Result<Self, GPUError> {
+        return Self::with_backend(gpu, TraceBackend::Auto);
+    }
+
+
+    /// Fails with GPUError::Unsupported when the hardware backend is forced on a GPU without ray queries
+    pub fn with_backend(gpu: GPU, backend: TraceBackend) -> Result<Self, GPUError> {
+        let backend = match backend {
+            TraceBackend::Auto if gpu.ray_query => TraceBackend::Hardware,
+            TraceBackend::Auto => TraceBackend::Software,
+            TraceBackend::Hardware if !gpu.ray_query => {
+                return Err(GPUError::Unsupported("hardware ray tracing needs VK_KHR_acceleration_structure and VK_KHR_ray_query".to_string()));
+            }
+            backend => backend,
+        };
+
+        let cs = match backend {
+            TraceBackend::Hardware => shaders::path_tracer_ray_query::load(gpu.device.clone())?,
+            _ => shaders::path_tracer::load(gpu.device.clone())?,
+        };
+
+        let pipeline = compute_pipeline(&gpu, cs)?;
+        let display_pipeline = compute_pipeline(&gpu, shaders::display::load(gpu.device.clone())?)?;
+        log::info!("Tracing with the {} backend", if backend == TraceBackend::Hardware { "hardware" } else { "software" });
+
+        return Ok(Self {
+            gpu: gpu,
+            pipeline: pipeline,
+            display_pipeline: display_pipeline,
+            backend: backend
+        });
+    }
+
+
+    pub fn gpu(&self) -> &GPU {
+        return &self.gpu;
+    }
+
+
+    /// Software or Hardware, whichever Auto turned into
+    pub fn backend(&self) -> TraceBackend {
+        return self.backend;
+    }
+
+
+    /// Renders every sample in one go, see start for a render that can be looked at or stopped on the way
+    pub fn render(&self, scene: &Scene, settings: &RenderSettings) -> Result<Framebuffer, RenderError> {
+        let mut render = self.start(scene, settings)?;
+
+        let start = std::time::Instant::now();
+        render.finish()?;
+        log::info!("Traced {}x{} at {} spp in {:.2?}", settings.width, settings.height, settings.samples_per_pixel, start.elapsed());
+
+        return Ok(render.display(&settings.display)?);
+    }
+
+
+    /// Uploads the scene and sets up an empty accumulation, without tracing anything yet
+    pub fn start(&self, scene: &Scene, settings: &RenderSettings) -> Result<ProgressiveRender<'_>, RenderError> {
+        validate(scene, settings)?;
+        return Ok(self.prepare(scene, settings)?);
+    }
+
+
+    /// Like start, but carries on from the accumulation of an earlier render of the same scene.
+    /// Samples are seeded by their index, so the result is the same as if it had never stopped
+    pub fn resume(&self, scene: &Scene, settings: &RenderSettings, accumulation: &Accumulation) -> Result<ProgressiveRender<'_>, RenderError> {
+        validate(scene, settings)?;
+
+        if accumulation.width != settings.width || accumulation.height != settings.height {
+            return Err(RenderError::InvalidSettings(format!(
+                "the accumulation is {}x{} but the image is {}x{}",
+                accumulation.width,
+                accumulation.height,
+                settings.width,
+                settings.height
+            )));
+        }
+
+        if accumulation.pixels.len() != settings.width as usize * settings.height as usize * 4 {
+            return Err(RenderError::InvalidSettings(format!(
+                "the accumulation has {} values for {}x{} pixels",
+                accumulation.pixels.len(),
+                settings.width,
+                settings.height
+            )));
+        }
+
+        let aovs: Vec<Aov> = accumulation.aovs.iter().map(|(aov, _)| *aov).collect();
+        if aovs != aov::in_shader_order(&settings.aovs) {
+            return Err(RenderError::InvalidSettings(format!("the accumulation has the AOVs {aovs:?} but the settings ask for {:?}", settings.aovs)));
+        }
+
+        if accumulation.aovs.iter().any(|(_, pixels)| pixels.len() != accumulation.pixels.len()) {
+            return Err(RenderError::InvalidSettings("the accumulation has AOVs of the wrong size".to_string()));
+        }
+
+        // more samples from another sequence or of another image would quietly blend two renders
+        if accumulation.seed != settings.seed {
+            return Err(RenderError::InvalidSettings(format!("the accumulation was rendered with seed {} but the settings have {}", accumulation.seed, settings.seed)));
+        }
+
+        if accumulation.fingerprint != fingerprint::fingerprint(scene, settings) {
+            return Err(RenderError::InvalidScene("the accumulation is of another scene, or of the same one with other bounces or light sampling".to_string()));
+        }
+
+        let mut render = self.prepare(scene, settings)?;
+        render.restore(accumulation)?;
+        return Ok(render);
+    }
+
+
+    fn prepare(&self, scene: &Scene, settings: &RenderSettings) -> Result<ProgressiveRender<'_>, GPUError> {
+        let gpu = &self.gpu;
+
+
+
+        /////////// Accumulation image
+
+        let image = gpu.create_image(ImageCreateInfo {
+            image_type: ImageType::Dim2d,
+            format: Format::R32G32B32A32_SFLOAT,
+            extent: [settings.width, settings.height, 1],
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_SRC | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        })?;
+
+        let view = ImageView::new_default(image.clone())?;
+
+        // one layer per AOV, with a spare when there are none since the binding can't be left empty
+        let aov_image = gpu.create_image(ImageCreateInfo {
+            image_type: ImageType::Dim2d,
+            format: Format::R32G32B32A32_SFLOAT,
+            extent: [settings.width, settings.height, 1],
+            array_layers: settings.aovs.len().max(1) as u32,
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_SRC | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        })?;
+
+        // the default view of a single layer image isn't an array
+        let aov_view = ImageView::new(aov_image.clone(), ImageViewCreateInfo {
+            view_type: ImageViewType::Dim2dArray,
+            ..ImageViewCreateInfo::from_image(&aov_image)
+        })?;
+
+
+
+
+        ////////// Buffers
+
+        let start = std::time::Instant::now();
+
+        let geometry = match self.backend {
+            TraceBackend::Hardware => geometry::upload_hardware(gpu, scene)?,
+            _ => geometry::upload_software(gpu, scene)?,
+        };
+
+        log::info!(
+            "Built acceleration structures over {} unique triangles and {} instances in {:.2?}",
+            geometry.triangles.len(),
+            geometry.instances.len(),
+            start.elapsed()
+        );
+
+        let texture_index = |texture: Option<u32>| texture.map_or(-1, |texture| texture as i32);
+
+        let materials = scene.materials.iter().map(|material| {
+            let (bsdf, conductor, ior) = match material.bsdf {
+                Bsdf::Diffuse => (0, ComplexIor::ALUMINIUM, 1.0),
+                Bsdf::Conductor(conductor) => (1, conductor, 1.0),
+                Bsdf::Dielectric { ior } => (2, ComplexIor::ALUMINIUM, ior),
+                Bsdf::Principled(principled) => (3, ComplexIor::ALUMINIUM, principled.ior),
+            };
+
+            let principled = match material.bsdf {
+                Bsdf::Principled(principled) => principled,
+                _ => Principled::default(),
+            };
+
+            return GPUMaterial {
+                albedo: material.albedo.extend(1.0),
+                emission: material.emission.extend(1.0),
+                eta: conductor.eta.extend(0.0),
+                k: conductor.k.extend(0.0),
+                metallic: material.metallic,
+                roughness: material.roughness,
+                albedo_texture: texture_index(material.albedo_texture),
+                emission_texture: texture_index(material.emission_texture),
+                metallic_roughness_texture: texture_index(material.metallic_roughness_texture),
+                bsdf: bsdf,
+                ior: ior,
+                specular: principled.specular,
+                anisotropy: principled.anisotropy,
+                sheen: principled.sheen,
+                sheen_tint: principled.sheen_tint,
+                clearcoat: principled.clearcoat,
+                clearcoat_roughness: principled.clearcoat_roughness,
+                transmission: principled.transmission,
+                subsurface: principled.subsurface,
+                _padding: 0
+            };
+        });
+
+        // every texture goes into one texel buffer, one RGBA8 texel per u32
+        let mut textures = Vec::with_capacity(scene.textures.len());
+        let mut texels = Vec::new();
+
+        for texture in &scene.textures {
+            textures.push(GPUTexture {
+                offset: texels.len() as u32,
+                width: texture.width,
+                height: texture.height
+            });
+            texels.extend(texture.pixels.chunks_exact(4).map(|texel| u32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]])));
+        }
+
+        // empty buffers can't be bound, so untextured scenes get a placeholder that is never read
+        if textures.is_empty() {
+            textures.push(GPUTexture { offset: 0, width: 1, height: 1 });
+            texels.push(u32::MAX);
+        }
+
+        let material_buffer = gpu.upload_buffer(materials, BufferUsage::STORAGE_BUFFER)?;
+        let texture_buffer = gpu.upload_buffer(textures, BufferUsage::STORAGE_BUFFER)?;
+        let texel_buffer = gpu.upload_buffer(texels, BufferUsage::STORAGE_BUFFER)?;
+        let camera_buffer = gpu.upload_buffer([GPUCamera::new(&scene.camera, settings.width, settings.height)], BufferUsage::UNIFORM_BUFFER)?;
+
+        let environment = scene.environment.as_ref().map(Environment::new);
+
+        let start = std::time::Instant::now();
+
+        let mut gathered = lights::gather(scene, &geometry.emitters, &geometry.instance_triangles, environment.as_ref());
+        let light_count = gathered.lights.len() as u32;
+        log::info!(
+            "Sampling {light_count} lights, counting every emissive triangle and the environment map, with a {} node light tree built in {:.2?}",
+            gathered.tree.nodes.len(),
+            start.elapsed()
+        );
+
+        // like the textures, the bindings need something in them even when nothing will read them
+        if gathered.lights.is_empty() {
+            gathered.lights.push(lights::GPULight::placeholder());
+        }
+        if gathered.tree.nodes.is_empty() {
+            gathered.tree.nodes.push(light_tree::GPULightNode::placeholder());
+        }
+        if gathered.emitter_lights.is_empty() {
+            gathered.emitter_lights.push(-1);
+        }
+
+        let light_buffer = gpu.upload_buffer(gathered.lights, BufferUsage::STORAGE_BUFFER)?;
+        let emitter_light_buffer = gpu.upload_buffer(gathered.emitter_lights, BufferUsage::STORAGE_BUFFER)?;
+        let light_node_buffer = gpu.upload_buffer(gathered.tree.nodes, BufferUsage::STORAGE_BUFFER)?;
+
+        // a width of zero tells the shader to use the background colour, which never reads these
+        let (environment_width, environment_height, environment_texels, environment_cdf) = match environment {
+            Some(environment) => (environment.width, environment.height, environment.texels, environment.cdf),
+            None => (0, 0, vec![0.0; 3], vec![1.0]),
+        };
+
+        let environment_texel_buffer = gpu.upload_buffer(environment_texels, BufferUsage::STORAGE_BUFFER)?;
+        let environment_cdf_buffer = gpu.upload_buffer(environment_cdf, BufferUsage::STORAGE_BUFFER)?;
+
+
+
+
+        //////// Descriptors
+
+        let descriptor_set = DescriptorSet::new(
+            gpu.descriptor_set_allocator.clone(),
+            self.pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::image_view(0, view),
+                WriteDescriptorSet::buffer(1, geometry.triangles),
+                WriteDescriptorSet::buffer(2, material_buffer),
+                WriteDescriptorSet::buffer(3, texture_buffer),
+                WriteDescriptorSet::buffer(4, texel_buffer),
+                match &geometry.acceleration {
+                    Acceleration::Software(nodes) => WriteDescriptorSet::buffer(5, nodes.clone()),
+                    Acceleration::Hardware(structures) => WriteDescriptorSet::acceleration_structure(5, structures.top_level.clone()),
+                },
+                WriteDescriptorSet::buffer(6, geometry.instances),
+                WriteDescriptorSet::image_view(7, aov_view),
+                WriteDescriptorSet::buffer(8, camera_buffer),
+                WriteDescriptorSet::buffer(9, light_buffer),
+                WriteDescriptorSet::buffer(10, environment_texel_buffer),
+                WriteDescriptorSet::buffer(11, environment_cdf_buffer),
+                WriteDescriptorSet::buffer(12, emitter_light_buffer),
+                WriteDescriptorSet::buffer(13, light_node_buffer),
+            ],
+            [],
+        )?;
+
+        let push_constants = PushConstants {
+            background: scene.background.extend(0.0),
+            width: settings.width,
+            height: settings.height,
+            // filled in by every dispatch
+            first_sample: 0,
+            samples: 0,
+            max_bounces: settings.max_bounces,
+            seed: settings.seed,
+            aov_mask: aov::mask(&settings.aovs),
+            light_count: light_count,
+            light_sampling: settings.light_sampling.index(),
+            light_selection: settings.light_selection.index(),
+            infinite_lights: gathered.infinite,
+            environment_width: environment_width,
+            environment_height: environment_height,
+            environment_rotation: scene.environment.as_ref().map_or(0.0, |environment| environment.rotation),
+        };
+
+        let mut render = ProgressiveRender::new(self, settings, image, aov_image, descriptor_set, push_constants, geometry.acceleration);
+        render.fingerprint = fingerprint::fingerprint(scene, settings);
+        return Ok(render);
+    }
+}
+
+
+
+
+fn compute_pipeline(gpu: &GPU, module: Arc<ShaderModule>) -> Result<Arc<ComputePipeline>, GPUError> {
+    let cs = module.entry_point("main").ok_or_else(|| GPUError::Unsupported("a compute shader without a main entry point".to_string()))?;
+    let stage = PipelineShaderStageCreateInfo::new(cs);
+
+    // the descriptor set layouts are checked against the device's limits, which many textures can go past
+    let layout_info = PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+        .into_pipeline_layout_create_info(gpu.device.clone())
+        .map_err(|err| GPUError::from(err.error))?;
+
+    let layout = PipelineLayout::new(gpu.device.clone(), layout_info)?;
+
+    let pipeline = ComputePipeline::new(gpu.device.clone(), None, ComputePipelineCreateInfo::stage_layout(stage, layout))?;
+    return Ok(pipeline);
+}
+
+
+
+
+fn validate(scene: &Scene, settings: &RenderSettings) -> Result<(), RenderError> {
+    if settings.width == 0 || settings.height == 0 {
+        return Err(RenderError::InvalidSettings(format!("image size {}x{} is empty", settings.width, settings.height)));
+    }
+
+    if settings.samples_per_pixel == 0 {
+        return Err(RenderError::InvalidSettings("at least one sample per pixel is needed".to_string()));
+    }
+
+    if settings.samples_per_dispatch == 0 {
+        return Err(RenderError::InvalidSettings("at least one sample per pixel per dispatch is needed".to_string()));
+    }
+
+    // the shader finds an AOV's layer by counting the ones before it, so each can only be there once
+    if aov::in_shader_order(&settings.aovs).len() != settings.aovs.len() {
+        return Err(RenderError::InvalidSettings(format!("the AOVs {:?} have duplicates", settings.aovs)));
+    }
+
+    let camera = &scene.camera;
+    if (camera.look_at - camera.position).cross(camera.up).length() == 0.0 {
+        return Err(RenderError::InvalidScene("the camera looks along its up vector, or at itself".to_string()));
+    }
+
+    if !(camera.fov > 0.0 && camera.fov < 180.0) {
+        return Err(RenderError::InvalidScene(format!("a camera fov of {} isn't between 0 and 180 degrees", camera.fov)));
+    }
+
+    let projection_valid = match camera.projection {
+        Projection::Perspective => true,
+        Projection::Orthographic { height } => height > 0.0 && height.is_finite(),
+        Projection::Fisheye { fov } => fov > 0.0 && fov <= 360.0,
+        Projection::Equirectangular { ipd } => ipd.is_none_or(|ipd| ipd >= 0.0 && ipd.is_finite()),
+    };
+
+    if !projection_valid {
+        return Err(RenderError::InvalidScene(format!("the camera projection {:?} is impossible", camera.projection)));
+    }
+
+    if let Some(lens) = camera.lens && !(lens.f_stop > 0.0 && lens.focus_distance > 0.0 && camera.sensor_height > 0.0) {
+        return Err(RenderError::InvalidScene(format!("the camera lens {lens:?} with a {}mm sensor is impossible", camera.sensor_height)));
+    }
+
+    for (index, instance) in scene.instances.iter().enumerate() {
+        if instance.mesh as usize >= scene.meshes.len() {
+            return Err(RenderError::InvalidScene(format!(
+                "instance {index} uses mesh {} but the scene only has {}",
+                instance.mesh,
+                scene.meshes.len()
+            )));
+        }
+
+        if let Some(material) = instance.material && material as usize >= scene.materials.len() {
+            return Err(RenderError::InvalidScene(format!(
+                "instance {index} uses material {material} but the scene only has {}",
+                scene.materials.len()
+            )));
+        }
+
+        if instance.transform.affine_inverse().is_none() {
+            return Err(RenderError::InvalidScene(format!("instance {index} has a transform that can't be inverted")));
+        }
+    }
+
+    // hardware instances only have 24 bits to say which instance they are
+    if scene.instances.len() >= 1 << 24 {
+        return Err(RenderError::InvalidScene(format!("{} instances is more than the 16777215 supported", scene.instances.len())));
+    }
+
+    let instanced = scene.instances.iter().any(|instance| !scene.meshes[instance.mesh as usize].triangles.is_empty());
+    if scene.triangles.is_empty() && !instanced {
+        return Err(RenderError::InvalidScene("the scene has no triangles".to_string()));
+    }
+
+    let mut triangles = scene.triangles.iter().chain(scene.meshes.iter().flat_map(|mesh| &mesh.triangles));
+    if let Some(triangle) = triangles.find(|triangle| triangle.material as usize >= scene.materials.len()) {
+        return Err(RenderError::InvalidScene(format!(
+            "a triangle uses material {} but the scene only has {}",
+            triangle.material,
+            scene.materials.len()
+        )));
+    }
+
+    for material in &scene.materials {
+        let valid = match material.bsdf {
+            Bsdf::Diffuse => true,
+            Bsdf::Conductor(ior) => [ior.eta, ior.k].iter().all(|value| value.min(Vec3::ZERO) == Vec3::ZERO && value.length().is_finite()),
+            Bsdf::Dielectric { ior } => ior > 0.0 && ior.is_finite(),
+            Bsdf::Principled(principled) => {
+                let fractions = [
+                    principled.specular,
+                    principled.anisotropy,
+                    principled.sheen,
+                    principled.sheen_tint,
+                    principled.clearcoat,
+                    principled.clearcoat_roughness,
+                    principled.transmission,
+                    principled.subsurface,
+                    material.metallic,
+                ];
+
+                fractions.iter().all(|value| (0.0..=1.0).contains(value)) && principled.ior > 0.0 && principled.ior.is_finite()
+            }
+        };
+
+        if !valid || !(0.0..=1.0).contains(&material.roughness) {
+            return Err(RenderError::InvalidScene(format!("a material has an impossible {:?} BSDF with roughness {}", material.bsdf, material.roughness)));
+        }
+
+        let textures = [material.albedo_texture, material.emission_texture, material.metallic_roughness_texture];
+
+        if let Some(texture) = textures.into_iter().flatten().find(|&texture| texture as usize >= scene.textures.len()) {
+            return Err(RenderError::InvalidScene(format!(
+                "a material uses texture {texture} but the scene only has {}",
+                scene.textures.len()
+            )));
+        }
+    }
+
+    if let Some(environment) = &scene.environment {
+        if environment.width == 0 || environment.height == 0 || environment.pixels.len() != environment.width as usize * environment.height as usize * 3 {
+            return Err(RenderError::InvalidScene(format!(
+                "a {}x{} environment map has {} values",
+                environment.width,
+                environment.height,
+                environment.pixels.len()
+            )));
+        }
+
+        // one infinite pixel would take every sample
+        if !(environment.pixels.iter().all(|value| value.is_finite()) && environment.intensity >= 0.0 && environment.intensity.is_finite()) {
+            return Err(RenderError::InvalidScene("the environment map isn't finite".to_string()));
+        }
+    }
+
+    if let Some(texture) = scene.textures.iter().find(|texture| texture.pixels.len() != texture.width as usize * texture.height as usize * 4 || texture.width == 0 || texture.height == 0) {
+        return Err(RenderError::InvalidScene(format!(
+            "a {}x{} texture has {} bytes of pixels",
+            texture.width,
+            texture.height,
+            texture.pixels.len()
+        )));
+    }
+
+    for (index, light) in scene.all_lights().enumerate() {
+        let valid = match *light {
+            Light::Point { .. } => true,
+            Light::Spot { direction, inner_angle, outer_angle, falloff, .. } => {
+                direction.length() > 0.0 && 0.0 <= inner_angle && inner_angle <= outer_angle && outer_angle <= std::f32::consts::PI && falloff > 0.0
+            }
+            Light::Directional { direction, angular_diameter, .. } => {
+                direction.length() > 0.0 && (0.0..std::f32::consts::PI).contains(&angular_diameter)
+            }
+        };
+
+        if !valid {
+            return Err(RenderError::InvalidScene(format!("light {index} is impossible: {light:?}")));
+        }
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,416 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::command_buffer::{CopyBufferToImageInfo, CopyImageToBufferInfo};
+use vulkano::descriptor_set::{DescriptorSet, WriteDescriptorSet};
+use vulkano::format::Format;
+use vulkano::image::view::ImageView;
+use vulkano::image::{Image, ImageCreateInfo, ImageType, ImageUsage};
+use vulkano::memory::allocator::MemoryTypeFilter;
+use vulkano::pipeline::{Pipeline, PipelineBindPoint};
+
+use super::aov::{self, Aov};
+use super::geometry::Acceleration;
+use super::{DisplayTransform, Framebuffer, PushConstants, RenderSettings, Renderer};
+use crate::gpu::GPUError;
+use crate::output::Layer;
+
+
+
+
+/// Starts every checkpoint file, the last byte is the version of the format
+const CHECKPOINT_MAGIC: [u8; 8] = *b"PTACCUM\x03";
+
+
+
+
+/// What a progressive render has summed up so far, in host memory
+#[derive(Clone, Debug)]
+pub struct Accumulation {
+    pub width: u32,
+    pub height: u32,
+    /// Samples per pixel that were taken
+    pub samples: u32,
+    /// RenderSettings::seed of the render, which picks the sample sequence
+    pub seed: u32,
+    /// Of the scene and the settings that change the image, see fingerprint::fingerprint
+    pub fingerprint: u64,
+    /// Linear RGBA sums, four floats per pixel in rows from top to bottom. Alpha counts the samples that went
+    /// into the pixel, which is fewer than samples where NaNs and infinities were dropped
+    pub pixels: Vec<f32>,
+    /// The rendered AOVs in Aov::ALL order, laid out like pixels. Only the filtered ones are sums
+    pub aovs: Vec<(Aov, Vec<f32>)>,
+}
+
+
+impl Accumulation {
+    /// The mean of the samples so far, linear RGBA with four floats per pixel
+    pub fn mean(&self) -> Vec<f32> {
+        return self.pixels.chunks_exact(4).flat_map(|pixel| {
+            let scale = one_over_count(pixel);
+            return pixel.iter().map(move |value| value * scale);
+        }).collect();
+    }
+
+
+    /// Every AOV as an output layer named after it, averaged where it's filtered and cut down to its channels
+    pub fn aov_layers(&self) -> Vec<Layer> {
+        return self.aovs.iter().map(|(aov, pixels)| {
+            let channels = aov.channels();
+            let filtered = aov.is_filtered();
+
+            // filtered AOVs are summed over the same samples as the colour
+            let pixels = pixels.chunks_exact(4).zip(self.pixels.chunks_exact(4)).flat_map(|(pixel, color)| {
+                let scale = if filtered { one_over_count(color) } else { 1.0 };
+                return pixel[..channels.len()].iter().map(move |value| value * scale);
+            });
+
+            return Layer {
+                name: aov.name().to_string(),
+                channels: channels.iter().map(|channel| channel.to_string()).collect(),
+                pixels: pixels.collect()
+            };
+        }).collect();
+    }
+
+
+    /// Writes a checkpoint that load can read back for Renderer::resume.
+    /// It goes to a temporary file first, so an interrupted save leaves the previous checkpoint intact
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temporary = OsString::from(path.as_os_str());
+        temporary.push(".partial");
+        let temporary = PathBuf::from(temporary);
+
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(&CHECKPOINT_MAGIC)?;
+
+        for value in [self.width, self.height, self.samples, self.seed] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        writer.write_all(&self.fingerprint.to_le_bytes())?;
+        writer.write_all(&(self.aovs.len() as u32).to_le_bytes())?;
+
+        for (aov, _) in &self.aovs {
+            writer.write_all(&aov.index().to_le_bytes())?;
+        }
+
+        for value in self.pixels.iter().chain(self.aovs.iter().flat_map(|(_, pixels)| pixels)) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        return fs::rename(&temporary, path);
+    }
+
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != CHECKPOINT_MAGIC {
+            return Err(invalid("not a render checkpoint, or one from another version".to_string()));
+        }
+
+        let mut read_u32 = || -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            return Ok(u32::from_le_bytes(bytes));
+        };
+
+        let [width, height, samples, seed] = [read_u32()?, read_u32()?, read_u32()?, read_u32()?];
+        let fingerprint = read_u32()? as u64 | (read_u32()? as u64) << 32;
+        let aov_count = read_u32()?;
+
+        let mut aovs = Vec::new();
+        for _ in 0..aov_count {
+            let index = read_u32()?;
+            let Some(&aov) = Aov::ALL.get(index as usize) else {
+                return Err(invalid(format!("checkpoint has an unknown AOV {index}")));
+            };
+            aovs.push(aov);
+        }
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        // counted in pixels, so a corrupt header can't overflow
+        let pixel_size = 4 * size_of::<f32>();
+        if bytes.len() % pixel_size != 0 || (bytes.len() / pixel_size) as u64 != width as u64 * height as u64 * (1 + aovs.len() as u64) {
+            return Err(invalid(format!("checkpoint has the wrong size for a {width}x{height} image with {} AOVs", aovs.len())));
+        }
+
+        let values: Vec<f32> = bytes.chunks_exact(4).map(|value| f32::from_le_bytes(value.try_into().unwrap())).collect();
+        let mut layers = values.chunks_exact(width as usize * height as usize * 4).map(|layer| layer.to_vec());
+
+        return Ok(Self {
+            width: width,
+            height: height,
+            samples: samples,
+            seed: seed,
+            fingerprint: fingerprint,
+            pixels: layers.next().unwrap_or_default(),
+            aovs: aovs.into_iter().zip(layers).collect()
+        });
+    }
+}
+
+
+/// Of an RGBA sum, zero for a pixel whose every sample was dropped
+fn one_over_count(pixel: &[f32]) -> f32 {
+    return if pixel[3] > 0.0 { 1.0 / pixel[3] } else { 0.0 };
+}
+
+
+
+
+/// A render on the GPU that samples are added to one dispatch at a time.
+/// It can be read back between dispatches, and dropped early without losing what was read
+pub struct ProgressiveRender<'a> {
+    renderer: &'a Renderer,
+    settings: RenderSettings,
+    /// Goes into the accumulation, filled in by Renderer::prepare
+    pub(super) fingerprint: u64,
+    /// RGBA32F sums, see Accumulation
+    image: Arc<Image>,
+    /// One RGBA32F layer per AOV in aovs, sums or single samples like Accumulation::aovs
+    aov_image: Arc<Image>,
+    aovs: Vec<Aov>,
+    descriptor_set: Arc<DescriptorSet>,
+    /// Everything but the sample range, which changes every dispatch
+    push_constants: PushConstants,
+    samples: u32,
+    // the descriptor set keeps the buffers alive, but not the bottom levels the top level points at
+    _acceleration: Acceleration,
+}
+
+
+impl<'a> ProgressiveRender<'a> {
+    pub(super) fn new(renderer: &'a Renderer, settings: &RenderSettings, image: Arc<Image>, aov_image: Arc<Image>, descriptor_set: Arc<DescriptorSet>, push_constants: PushConstants, acceleration: Acceleration) -> Self {
+        return Self {
+            renderer: renderer,
+            settings: settings.clone(),
+            fingerprint: 0,
+            image: image,
+            aov_image: aov_image,
+            aovs: aov::in_shader_order(&settings.aovs),
+            descriptor_set: descriptor_set,
+            push_constants: push_constants,
+            samples: 0,
+            _acceleration: acceleration
+        };
+    }
+
+
+    pub fn settings(&self) -> &RenderSettings {
+        return &self.settings;
+    }
+
+
+    /// Samples per pixel accumulated so far
+    pub fn samples(&self) -> u32 {
+        return self.samples;
+    }
+
+
+    /// Whether all of settings.samples_per_pixel have been accumulated
+    pub fn is_done(&self) -> bool {
+        return self.samples >= self.settings.samples_per_pixel;
+    }
+
+
+    /// Dispatches up to settings.samples_per_dispatch more samples per pixel and waits for them,
+    /// never going past samples_per_pixel. Returns how many were added, zero once the render is done
+    pub fn step(&mut self) -> Result<u32, GPUError> {
+        let samples = self.settings.samples_per_dispatch.min(self.settings.samples_per_pixel.saturating_sub(self.samples));
+        if samples == 0 {
+            return Ok(0);
+        }
+
+        let push_constants = PushConstants {
+            first_sample: self.samples,
+            samples: samples,
+            ..self.push_constants
+        };
+
+        let gpu = &self.renderer.gpu;
+        let pipeline = &self.renderer.pipeline;
+        let mut builder = gpu.command_buffer(&gpu.compute_queue)?;
+
+        builder
+            .bind_pipeline_compute(pipeline.clone())?
+            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline.layout().clone(), 0, self.descriptor_set.clone())?
+            .push_constants(pipeline.layout().clone(), 0, push_constants)?;
+
+        // one invocation per pixel, in 8x8 workgroups
+        unsafe {
+            builder.dispatch([self.settings.width.div_ceil(8), self.settings.height.div_ceil(8), 1])?;
+        }
+
+        gpu.run(builder.build()?)?;
+        self.samples += samples;
+
+        log::debug!("Accumulated {}/{} samples per pixel", self.samples, self.settings.samples_per_pixel);
+        return Ok(samples);
+    }
+
+
+    /// Steps until the render is done
+    pub fn finish(&mut self) -> Result<(), GPUError> {
+        while self.step()? > 0 {}
+        return Ok(());
+    }
+
+
+    /// The AOVs being rendered, in the order of Aov::ALL
+    pub fn aovs(&self) -> &[Aov] {
+        return &self.aovs;
+    }
+
+
+    /// Copies the sums so far back to the host, with the AOVs
+    pub fn read(&self) -> Result<Accumulation, GPUError> {
+        let gpu = &self.renderer.gpu;
+        let layer_size = self.settings.width as usize * self.settings.height as usize * 4;
+
+        let buffer = gpu.buffer_from_iter(
+            (0..layer_size).map(|_| 0.0f32),
+            BufferUsage::TRANSFER_DST,
+            MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS
+        )?;
+
+        let mut builder = gpu.command_buffer(&gpu.transfer_queue)?;
+        builder.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(self.image.clone(), buffer.clone()))?;
+
+        // the AOV image has a layer even when there are none, so the descriptor set is complete
+        let aov_buffer = if self.aovs.is_empty() {
+            None
+        } else {
+            let aov_buffer = gpu.buffer_from_iter(
+                (0..layer_size * self.aovs.len()).map(|_| 0.0f32),
+                BufferUsage::TRANSFER_DST,
+                MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS
+            )?;
+
+            builder.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(self.aov_image.clone(), aov_buffer.clone()))?;
+            Some(aov_buffer)
+        };
+
+        gpu.run_transfer(builder.build()?)?;
+
+        let pixels = buffer.read()?.to_vec();
+
+        // the layers come out one after the other
+        let aovs = match aov_buffer {
+            Some(aov_buffer) => self.aovs.iter().copied().zip(aov_buffer.read()?.chunks_exact(layer_size).map(|layer| layer.to_vec())).collect(),
+            None => Vec::new(),
+        };
+
+        return Ok(Accumulation {
+            width: self.settings.width,
+            height: self.settings.height,
+            samples: self.samples,
+            seed: self.settings.seed,
+            fingerprint: self.fingerprint,
+            pixels: pixels,
+            aovs: aovs
+        });
+    }
+
+
+    /// Runs the sums so far through a display transform on the GPU and reads back the 8 bit result
+    pub fn display(&self, transform: &DisplayTransform) -> Result<Framebuffer, GPUError> {
+        let gpu = &self.renderer.gpu;
+        let pipeline = &self.renderer.display_pipeline;
+        let (width, height) = (self.settings.width, self.settings.height);
+
+        let display = gpu.create_image(ImageCreateInfo {
+            image_type: ImageType::Dim2d,
+            format: Format::R8G8B8A8_UNORM,
+            extent: [width, height, 1],
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_SRC,
+            ..Default::default()
+        })?;
+
+        let output_buffer = gpu.buffer_from_iter(
+            (0..width as usize * height as usize * 4).map(|_| 0u8),
+            BufferUsage::TRANSFER_DST,
+            MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS
+        )?;
+
+        let descriptor_set = DescriptorSet::new(
+            gpu.descriptor_set_allocator.clone(),
+            pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::image_view(0, ImageView::new_default(self.image.clone())?),
+                WriteDescriptorSet::image_view(1, ImageView::new_default(display.clone())?),
+            ],
+            [],
+        )?;
+
+        let mut builder = gpu.command_buffer(&gpu.compute_queue)?;
+
+        builder
+            .bind_pipeline_compute(pipeline.clone())?
+            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline.layout().clone(), 0, descriptor_set)?
+            .push_constants(pipeline.layout().clone(), 0, transform.push_constants(width, height))?;
+
+        unsafe {
+            builder.dispatch([width.div_ceil(8), height.div_ceil(8), 1])?;
+        }
+
+        let display_command_buffer = builder.build()?;
+
+        // the readback goes through the transfer queue
+        let mut builder = gpu.command_buffer(&gpu.transfer_queue)?;
+        builder.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(display, output_buffer.clone()))?;
+
+        gpu.run_then_transfer(display_command_buffer, builder.build()?)?;
+
+        let pixels = output_buffer.read()?.to_vec();
+
+        return Ok(Framebuffer {
+            width: width,
+            height: height,
+            pixels: pixels
+        });
+    }
+
+
+    /// Replaces the sums with a previous render's, which the caller has checked is the right size
+    /// and has the same AOVs
+    pub(super) fn restore(&mut self, accumulation: &Accumulation) -> Result<(), GPUError> {
+        let gpu = &self.renderer.gpu;
+
+        let staging = gpu.buffer_from_iter(
+            accumulation.pixels.iter().copied(),
+            BufferUsage::TRANSFER_SRC,
+            MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+        )?;
+
+        let mut builder = gpu.command_buffer(&gpu.transfer_queue)?;
+        builder.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(staging, self.image.clone()))?;
+
+        if !accumulation.aovs.is_empty() {
+            let aov_staging = gpu.buffer_from_iter(
+                accumulation.aovs.iter().flat_map(|(_, pixels)| pixels.iter().copied()).collect::<Vec<_>>(),
+                BufferUsage::TRANSFER_SRC,
+                MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+            )?;
+
+            builder.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(aov_staging, self.aov_image.clone()))?;
+        }
+
+        gpu.run_transfer(builder.build()?)?;
+
+        self.samples = accumulation.samples;
+        return Ok(());
+    }
+}
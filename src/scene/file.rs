@@ -0,0 +1,1334 @@
+//! Scene files are TOML. A complete example, every table can be left out except `camera`,
+//! which is only optional when a glTF mesh brings its own:
+//!
+//! ```toml
+//! # required, the format version this file was written for
+//! version = 1
+//!
+//! [camera]
+//! position = [0.0, 1.0, 3.9]
+//! look_at = [0.0, 1.0, 0.0]
+//! up = [0.0, 1.0, 0.0]        # default +y
+//! # "perspective" (default), "orthographic", "fisheye" or "equirectangular"
+//! projection = "perspective"
+//! fov = 40.0                  # vertical, in degrees, default 40. Fisheyes: across the image circle, default 180
+//! # height = 2.0              # orthographic: of the image, in scene units
+//! # ipd = 0.064               # equirectangular: eye distance for over-under stereo, mono without it
+//! # focal_length = 50.0       # perspective: in mm instead of fov
+//! sensor_height = 24.0        # in mm, default 24 (full frame)
+//! # depth of field for perspective cameras, a pinhole without f_stop
+//! f_stop = 2.8
+//! focus_distance = 3.9        # default the distance to look_at
+//! blades = 6                  # polygonal bokeh, default 0 (round)
+//! blade_rotation = 0.0        # in degrees
+//!
+//! [environment]
+//! color = [0.0, 0.0, 0.0]     # radiance of rays that leave the scene, default black
+//! # or an equirectangular .hdr or .exr image around the scene instead, relative to this file
+//! # map = "skies/studio.hdr"
+//! # rotation = 0.0            # around +y in degrees
+//! # intensity = 1.0
+//!
+//! # or a clear daylight sky with the sun in it, instead of the color or map. North is -z and east +x
+//! # [environment.sky]
+//! # turbidity = 3.0           # haze, from 2 (very clear) to 10, default 3
+//! # intensity = 1.0
+//! # sun_elevation = 30.0      # in degrees above the horizon
+//! # sun_azimuth = 180.0       # in degrees from north towards east, default 180 (south)
+//! # # or where and when instead of the angles
+//! # latitude = 48.14          # in degrees, north and east are positive
+//! # longitude = 11.58
+//! # date = 2024-06-21
+//! # time = 15:30:00           # local time
+//! # utc_offset = 2.0          # hours ahead of UTC, default 0
+//!
+//! # defaults for the render, command line flags take priority
+//! [render]
+//! width = 1024
+//! height = 1024
+//! spp = 64
+//! max_bounces = 8
+//! seed = 0
+//!
+//! [materials.white]
+//! type = "diffuse"            # the default
+//! albedo = [0.73, 0.73, 0.73] # default black, and white for the other types which it tints
+//! emission = [0.0, 0.0, 0.0]  # default black
+//!
+//! # GGX metal, either a preset ("gold", "copper", "aluminium" or "silver") or an eta and k
+//! [materials.gold]
+//! type = "conductor"
+//! metal = "gold"
+//! # eta = [0.143, 0.374, 1.442]
+//! # k = [3.983, 2.385, 1.603]
+//! roughness = 0.2             # 0 (default) is a mirror
+//!
+//! [materials.glass]
+//! type = "dielectric"
+//! ior = 1.5                   # the default
+//! roughness = 0.0
+//!
+//! # Disney's principled BSDF like Blender's, these are the defaults
+//! [materials.paint]
+//! type = "principled"
+//! albedo = [0.8, 0.8, 0.8]    # the base colour
+//! metallic = 0.0
+//! roughness = 0.5
+//! specular = 0.5
+//! anisotropy = 0.0
+//! sheen = 0.0
+//! sheen_tint = 0.5
+//! clearcoat = 0.0
+//! clearcoat_roughness = 0.03
+//! transmission = 0.0
+//! ior = 1.5
+//! subsurface = 0.0
+//!
+//! # an inline mesh, indices default to every three positions forming a triangle
+//! [[meshes]]
+//! material = "white"
+//! positions = [[-1.0, 0.0, -1.0], [1.0, 0.0, -1.0], [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0]]
+//! indices = [[0, 1, 2], [0, 2, 3]]
+//!
+//! # a referenced mesh, the path is relative to this file and the file holds
+//! # `positions` and optionally `indices` like an inline mesh
+//! [[meshes]]
+//! material = "white"
+//! file = "meshes/box.toml"
+//!
+//! # Wavefront OBJ files bring their own MTL materials, which `material` overrides.
+//! # `normals` decides how missing vertex normals are made, "smooth" (default) or "flat"
+//! [[meshes]]
+//! file = "meshes/bunny.obj"
+//! normals = "flat"
+//!
+//! # glTF 2.0 files (.gltf or .glb) bring their node hierarchy, materials, textures,
+//! # lights and cameras. The first camera is used when there is no [camera] table
+//! [[meshes]]
+//! file = "models/room.glb"
+//!
+//! # any mesh with an emissive material is an area light. This one is a rectangle spanned by two edges from a corner
+//! [[lights]]
+//! type = "quad"
+//! corner = [-0.25, 1.99, -0.25]
+//! edge_a = [0.5, 0.0, 0.0]
+//! edge_b = [0.0, 0.0, 0.5]
+//! emission = [15.0, 15.0, 15.0]
+//!
+//! # intensities and irradiances are already multiplied by the light's colour
+//! [[lights]]
+//! type = "point"
+//! position = [0.0, 1.5, 0.0]
+//! intensity = [2.0, 2.0, 2.0]
+//!
+//! [[lights]]
+//! type = "spot"
+//! position = [0.0, 1.9, 1.0]
+//! direction = [0.0, -1.0, -0.5]
+//! intensity = [10.0, 10.0, 10.0]
+//! inner_angle = 0.0           # in degrees from the direction, these are the defaults
+//! outer_angle = 45.0
+//! falloff = 1.0               # exponent of the fade between the cones
+//!
+//! [[lights]]
+//! type = "directional"
+//! direction = [-0.3, -1.0, -0.2] # the way the light travels
+//! irradiance = [3.0, 3.0, 3.0]
+//! angular_diameter = 0.53     # in degrees, default 0 for hard shadows
+//! ```
+//!
+//! `Scene::load` also takes a .gltf or .glb file directly, framing the whole scene when the file has no camera.
+//!
+//! Unknown keys are errors so typos don't go unnoticed, and every error carries the line and column it refers to.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use toml::Spanned;
+use toml::value::Datetime;
+
+use crate::math::Vec3;
+use super::{Bsdf, Camera, ComplexIor, EnvironmentMap, GeneratedNormals, Light, Material, ObjOptions, Principled, Projection, Scene, SceneRenderSettings, Sky, SunPosition, ThinLens};
+
+
+
+
+/// The newest scene file version this build understands
+pub const FORMAT_VERSION: u32 = 1;
+
+
+#[derive(Debug)]
+pub enum SceneError {
+    /// A scene or mesh file couldn't be read
+    Io { path: PathBuf, source: io::Error },
+    /// A file was read but isn't valid, lines and columns start at 1
+    Invalid { path: PathBuf, line: usize, column: usize, message: String },
+    /// A mesh format importer rejected a file
+    Import { path: PathBuf, message: String },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            SceneError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            SceneError::Invalid { path, line, column, message } => write!(f, "{}:{line}:{column}: {message}", path.display()),
+            SceneError::Import { path, message } => write!(f, "{}: {message}", path.display()),
+        };
+    }
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return match self {
+            SceneError::Io { source, .. } => Some(source),
+            SceneError::Invalid { .. } | SceneError::Import { .. } => None,
+        };
+    }
+}
+
+
+
+
+/// A file being loaded, kept around to turn byte spans into lines and columns
+struct Source {
+    path: PathBuf,
+    text: String,
+}
+
+impl Source {
+    fn read(path: &Path) -> Result<Self, SceneError> {
+        let text = std::fs::read_to_string(path).map_err(|err| SceneError::Io { path: path.to_path_buf(), source: err })?;
+        return Ok(Self { path: path.to_path_buf(), text: text });
+    }
+
+
+    fn parse<T>(&self) -> Result<T, SceneError> where T: DeserializeOwned {
+        return toml::from_str(&self.text).map_err(|err| {
+            let span = err.span().unwrap_or(0..0);
+            self.error(span, err.message())
+        });
+    }
+
+
+    fn error(&self, span: Range<usize>, message: impl Into<String>) -> SceneError {
+        let before = &self.text[..span.start.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1;
+
+        return SceneError::Invalid {
+            path: self.path.clone(),
+            line: line,
+            column: column,
+            message: message.into()
+        };
+    }
+}
+
+
+
+
+/////////// File layout
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    version: Spanned<u32>,
+    camera: Option<CameraDesc>,
+    #[serde(default)]
+    environment: EnvironmentDesc,
+    #[serde(default)]
+    render: RenderDesc,
+    #[serde(default)]
+    materials: BTreeMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    meshes: Vec<Spanned<MeshDesc>>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightDesc>>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    position: [f32; 3],
+    look_at: [f32; 3],
+    up: Option<[f32; 3]>,
+    projection: Option<Spanned<ProjectionDesc>>,
+    fov: Option<Spanned<f32>>,
+    height: Option<Spanned<f32>>,
+    ipd: Option<Spanned<f32>>,
+    focal_length: Option<Spanned<f32>>,
+    sensor_height: Option<Spanned<f32>>,
+    f_stop: Option<Spanned<f32>>,
+    focus_distance: Option<Spanned<f32>>,
+    blades: Option<Spanned<u32>>,
+    blade_rotation: Option<Spanned<f32>>,
+}
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ProjectionDesc {
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    color: Option<Spanned<[f32; 3]>>,
+    map: Option<Spanned<PathBuf>>,
+    rotation: Option<Spanned<f32>>,
+    intensity: Option<Spanned<f32>>,
+    sky: Option<Spanned<SkyDesc>>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDesc {
+    turbidity: Option<Spanned<f32>>,
+    intensity: Option<Spanned<f32>>,
+    sun_elevation: Option<Spanned<f32>>,
+    sun_azimuth: Option<Spanned<f32>>,
+    latitude: Option<Spanned<f32>>,
+    longitude: Option<Spanned<f32>>,
+    date: Option<Spanned<Datetime>>,
+    time: Option<Spanned<Datetime>>,
+    utc_offset: Option<Spanned<f32>>,
+}
+
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderDesc {
+    width: Option<Spanned<u32>>,
+    height: Option<Spanned<u32>>,
+    spp: Option<Spanned<u32>>,
+    max_bounces: Option<u32>,
+    seed: Option<u32>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type", default)]
+    kind: MaterialKindDesc,
+    albedo: Option<[f32; 3]>,
+    emission: Option<Spanned<[f32; 3]>>,
+    roughness: Option<Spanned<f32>>,
+    metal: Option<Spanned<String>>,
+    eta: Option<[f32; 3]>,
+    k: Option<[f32; 3]>,
+    ior: Option<Spanned<f32>>,
+    metallic: Option<Spanned<f32>>,
+    specular: Option<Spanned<f32>>,
+    anisotropy: Option<Spanned<f32>>,
+    sheen: Option<Spanned<f32>>,
+    sheen_tint: Option<Spanned<f32>>,
+    clearcoat: Option<Spanned<f32>>,
+    clearcoat_roughness: Option<Spanned<f32>>,
+    transmission: Option<Spanned<f32>>,
+    subsurface: Option<Spanned<f32>>,
+}
+
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum MaterialKindDesc {
+    #[default]
+    Diffuse,
+    Conductor,
+    Dielectric,
+    Principled,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    material: Option<Spanned<String>>,
+    positions: Option<Spanned<Vec<[f32; 3]>>>,
+    indices: Option<Spanned<Vec<[u32; 3]>>>,
+    file: Option<Spanned<PathBuf>>,
+    normals: Option<NormalsDesc>,
+}
+
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum NormalsDesc {
+    Smooth,
+    Flat,
+}
+
+
+/// The contents of a referenced mesh file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshFileDesc {
+    positions: Spanned<Vec<[f32; 3]>>,
+    indices: Option<Spanned<Vec<[u32; 3]>>>,
+}
+
+
+/// Every key of every type of light, like MaterialDesc, so each value can keep its span
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    #[serde(rename = "type")]
+    kind: LightKindDesc,
+    corner: Option<Spanned<[f32; 3]>>,
+    edge_a: Option<Spanned<[f32; 3]>>,
+    edge_b: Option<Spanned<[f32; 3]>>,
+    emission: Option<Spanned<[f32; 3]>>,
+    position: Option<Spanned<[f32; 3]>>,
+    direction: Option<Spanned<[f32; 3]>>,
+    intensity: Option<Spanned<[f32; 3]>>,
+    irradiance: Option<Spanned<[f32; 3]>>,
+    inner_angle: Option<Spanned<f32>>,
+    outer_angle: Option<Spanned<f32>>,
+    falloff: Option<Spanned<f32>>,
+    angular_diameter: Option<Spanned<f32>>,
+}
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum LightKindDesc {
+    Quad,
+    Point,
+    Spot,
+    Directional,
+}
+
+
+
+
+/////////// Loading
+
+impl Scene {
+    /// Loads and validates a scene file, see the module docs for the format
+    pub fn load(path: impl AsRef<Path>) -> Result<Scene, SceneError> {
+        if has_extension(path.as_ref(), &["gltf", "glb"]) {
+            let mut scene = Scene::default();
+            let camera = scene.load_gltf(path)?;
+
+            scene.camera = camera.unwrap_or_else(|| frame(&scene));
+            return Ok(scene);
+        }
+
+        let source = Source::read(path.as_ref())?;
+        let directory = path.as_ref().parent().unwrap_or(Path::new(""));
+        return load_scene(&source, directory);
+    }
+}
+
+
+/// A scene file that has been read, with paths in it relative to directory
+fn load_scene(source: &Source, directory: &Path) -> Result<Scene, SceneError> {
+    let desc: SceneDesc = source.parse()?;
+
+    if *desc.version.get_ref() > FORMAT_VERSION {
+        return Err(source.error(
+            desc.version.span(),
+            format!("scene format version {} is newer than the supported version {FORMAT_VERSION}", desc.version.get_ref())
+        ));
+    }
+
+    if *desc.version.get_ref() == 0 {
+        return Err(source.error(desc.version.span(), "scene format versions start at 1"));
+    }
+
+    let mut scene = Scene {
+        background: desc.environment.color.as_ref().map_or(Vec3::ZERO, |color| Vec3::from(*color.get_ref())),
+        environment: load_environment(source, directory, &desc.environment)?,
+        render_settings: load_render_settings(source, &desc.render)?,
+        ..Default::default()
+    };
+
+    // BTreeMap keeps the material indices stable between runs
+    let mut material_indices = BTreeMap::new();
+    for (name, material) in &desc.materials {
+        let index = scene.add_material(load_material(source, material)?);
+        material_indices.insert(name.as_str(), index);
+    }
+
+    let mut gltf_camera = None;
+
+    for mesh in &desc.meshes {
+        let material = match &mesh.get_ref().material {
+            Some(name) => match material_indices.get(name.get_ref().as_str()) {
+                Some(&index) => Some(index),
+                None => return Err(source.error(name.span(), format!("unknown material \"{}\"", name.get_ref()))),
+            },
+            None => None,
+        };
+
+        if let Some(camera) = load_mesh(&mut scene, source, directory, mesh, material)? {
+            gltf_camera.get_or_insert(camera);
+        }
+    }
+
+    scene.camera = match (&desc.camera, gltf_camera) {
+        (Some(camera), _) => load_camera(source, camera)?,
+        (None, Some(camera)) => camera,
+        (None, None) => return Err(source.error(0..0, "the scene needs a [camera], none of its glTF files has one")),
+    };
+
+    if let Some(sky) = load_sky(source, &desc.environment)? {
+        scene.set_sky(&sky);
+    }
+
+    for light in &desc.lights {
+        load_light(&mut scene, source, light)?;
+    }
+
+    return Ok(scene);
+}
+
+
+fn load_environment(source: &Source, directory: &Path, desc: &EnvironmentDesc) -> Result<Option<EnvironmentMap>, SceneError> {
+    let Some(map) = &desc.map else {
+        if let Some(span) = desc.rotation.as_ref().map(Spanned::span).or(desc.intensity.as_ref().map(Spanned::span)) {
+            return Err(source.error(span, "rotation and intensity only apply to an environment map"));
+        }
+        return Ok(None);
+    };
+
+    if let Some(color) = &desc.color {
+        return Err(source.error(color.span(), "an environment has either a color or a map"));
+    }
+
+    let intensity = match &desc.intensity {
+        Some(intensity) if !(*intensity.get_ref() >= 0.0 && intensity.get_ref().is_finite()) => {
+            return Err(source.error(intensity.span(), "intensity can't be negative"));
+        }
+        Some(intensity) => *intensity.get_ref(),
+        None => 1.0,
+    };
+
+    let environment = EnvironmentMap::load(directory.join(map.get_ref()))?;
+
+    return Ok(Some(EnvironmentMap {
+        rotation: desc.rotation.as_ref().map_or(0.0, |rotation| rotation.get_ref().to_radians()),
+        intensity: intensity,
+        ..environment
+    }));
+}
+
+
+fn load_sky(source: &Source, desc: &EnvironmentDesc) -> Result<Option<Sky>, SceneError> {
+    let Some(spanned) = &desc.sky else {
+        return Ok(None);
+    };
+
+    let span = spanned.span();
+    let sky = spanned.get_ref();
+
+    let environment_keys = [
+        desc.color.as_ref().map(Spanned::span),
+        desc.map.as_ref().map(Spanned::span),
+        desc.rotation.as_ref().map(Spanned::span),
+        desc.intensity.as_ref().map(Spanned::span),
+    ];
+    if let Some(key) = environment_keys.into_iter().flatten().next() {
+        return Err(source.error(key, "a sky replaces the environment's color and map, and has an intensity of its own"));
+    }
+
+    let value = |value: &Option<Spanned<f32>>| value.as_ref().map(|value| *value.get_ref());
+    let span_of_key = |value: &Option<Spanned<f32>>| value.as_ref().map(Spanned::span);
+    // or of the whole table for keys that were left out
+    let span_of = |value: &Option<Spanned<f32>>| span_of_key(value).unwrap_or(span.clone());
+
+    let turbidity = value(&sky.turbidity).unwrap_or(3.0);
+    if !(2.0..=10.0).contains(&turbidity) {
+        return Err(source.error(span_of(&sky.turbidity), "a sky's turbidity goes from 2 to 10"));
+    }
+
+    let intensity = value(&sky.intensity).unwrap_or(1.0);
+    if !(intensity >= 0.0 && intensity.is_finite()) {
+        return Err(source.error(span_of(&sky.intensity), "intensity can't be negative"));
+    }
+
+    let angles = [span_of_key(&sky.sun_elevation), span_of_key(&sky.sun_azimuth)].into_iter().flatten().next();
+    let location = [
+        span_of_key(&sky.latitude),
+        span_of_key(&sky.longitude),
+        sky.date.as_ref().map(Spanned::span),
+        sky.time.as_ref().map(Spanned::span),
+        span_of_key(&sky.utc_offset),
+    ].into_iter().flatten().next();
+
+    let sun = match (angles, location) {
+        (Some(_), Some(location)) => return Err(source.error(location, "the sun goes either by sun_elevation and sun_azimuth or by latitude, longitude, date and time")),
+        (None, None) => return Err(source.error(span, "a sky needs a sun_elevation, or a latitude, longitude, date and time")),
+        (Some(_), None) => {
+            let Some(elevation) = value(&sky.sun_elevation) else {
+                return Err(source.error(span_of(&sky.sun_azimuth), "a sky with a sun_azimuth needs a sun_elevation too"));
+            };
+            if !(-90.0..=90.0).contains(&elevation) {
+                return Err(source.error(span_of(&sky.sun_elevation), "sun_elevation goes from -90 to 90 degrees"));
+            }
+
+            SunPosition::Angles {
+                elevation: elevation.to_radians(),
+                azimuth: value(&sky.sun_azimuth).unwrap_or(180.0).to_radians()
+            }
+        }
+        (None, Some(_)) => {
+            let (Some(latitude), Some(longitude), Some(date), Some(time)) = (value(&sky.latitude), value(&sky.longitude), &sky.date, &sky.time) else {
+                return Err(source.error(span, "placing the sun by location needs a latitude, longitude, date and time"));
+            };
+            if !(-90.0..=90.0).contains(&latitude) {
+                return Err(source.error(span_of(&sky.latitude), "latitude goes from -90 to 90 degrees"));
+            }
+            if !(-180.0..=180.0).contains(&longitude) {
+                return Err(source.error(span_of(&sky.longitude), "longitude goes from -180 to 180 degrees"));
+            }
+
+            let (Some(date), None) = (date.get_ref().date, date.get_ref().time) else {
+                return Err(source.error(date.span(), "date has to be a plain date like 2024-06-21"));
+            };
+            let (None, Some(time)) = (time.get_ref().date, time.get_ref().time) else {
+                return Err(source.error(time.span(), "time has to be a plain local time like 15:30:00"));
+            };
+
+            SunPosition::Location {
+                latitude: latitude,
+                longitude: longitude,
+                year: date.year as i32,
+                month: date.month as u32,
+                day: date.day as u32,
+                time: time.hour as f32 + time.minute as f32 / 60.0 + time.second as f32 / 3600.0,
+                utc_offset: value(&sky.utc_offset).unwrap_or(0.0)
+            }
+        }
+    };
+
+    return Ok(Some(Sky {
+        sun: sun,
+        turbidity: turbidity,
+        intensity: intensity
+    }));
+}
+
+
+fn load_light(scene: &mut Scene, source: &Source, light: &Spanned<LightDesc>) -> Result<(), SceneError> {
+    let desc = light.get_ref();
+    let kind = desc.kind;
+
+    let keys = [
+        ("corner", desc.corner.as_ref().map(Spanned::span), kind == LightKindDesc::Quad),
+        ("edge_a", desc.edge_a.as_ref().map(Spanned::span), kind == LightKindDesc::Quad),
+        ("edge_b", desc.edge_b.as_ref().map(Spanned::span), kind == LightKindDesc::Quad),
+        ("emission", desc.emission.as_ref().map(Spanned::span), kind == LightKindDesc::Quad),
+        ("position", desc.position.as_ref().map(Spanned::span), kind == LightKindDesc::Point || kind == LightKindDesc::Spot),
+        ("direction", desc.direction.as_ref().map(Spanned::span), kind == LightKindDesc::Spot || kind == LightKindDesc::Directional),
+        ("intensity", desc.intensity.as_ref().map(Spanned::span), kind == LightKindDesc::Point || kind == LightKindDesc::Spot),
+        ("irradiance", desc.irradiance.as_ref().map(Spanned::span), kind == LightKindDesc::Directional),
+        ("inner_angle", desc.inner_angle.as_ref().map(Spanned::span), kind == LightKindDesc::Spot),
+        ("outer_angle", desc.outer_angle.as_ref().map(Spanned::span), kind == LightKindDesc::Spot),
+        ("falloff", desc.falloff.as_ref().map(Spanned::span), kind == LightKindDesc::Spot),
+        ("angular_diameter", desc.angular_diameter.as_ref().map(Spanned::span), kind == LightKindDesc::Directional),
+    ];
+
+    for (name, span, applies) in keys {
+        if let Some(span) = span && !applies {
+            return Err(source.error(span, format!("{name} doesn't apply to this type of light")));
+        }
+    }
+
+    let required = |name: &str, value: &Option<Spanned<[f32; 3]>>| -> Result<Spanned<[f32; 3]>, SceneError> {
+        return value.clone().ok_or_else(|| source.error(light.span(), format!("this type of light needs {name}")));
+    };
+
+    let vector = |name: &str, value: &Option<Spanned<[f32; 3]>>| -> Result<Vec3, SceneError> {
+        return Ok(Vec3::from(*required(name, value)?.get_ref()));
+    };
+
+    let color = |name: &str, value: &Option<Spanned<[f32; 3]>>| -> Result<Vec3, SceneError> {
+        return load_color(source, name, &required(name, value)?);
+    };
+
+    let direction = || -> Result<Vec3, SceneError> {
+        let value = required("direction", &desc.direction)?;
+        let direction = Vec3::from(*value.get_ref());
+        if !(direction.length() > 0.0 && direction.length().is_finite()) {
+            return Err(source.error(value.span(), "a light's direction can't be zero"));
+        }
+        return Ok(direction.normalize());
+    };
+
+    let number = |value: &Option<Spanned<f32>>, default: f32| value.as_ref().map_or((default, light.span()), |value| (*value.get_ref(), value.span()));
+
+    let light = match kind {
+        LightKindDesc::Quad => {
+            let corner = vector("corner", &desc.corner)?;
+            let edge_a = vector("edge_a", &desc.edge_a)?;
+            let edge_b = vector("edge_b", &desc.edge_b)?;
+
+            let material = scene.add_material(Material::emissive(color("emission", &desc.emission)?));
+            scene.add_quad(corner, corner + edge_a, corner + edge_a + edge_b, corner + edge_b, material);
+            return Ok(());
+        }
+        LightKindDesc::Point => Light::Point {
+            position: vector("position", &desc.position)?,
+            intensity: color("intensity", &desc.intensity)?
+        },
+        LightKindDesc::Spot => {
+            let (inner_angle, inner_span) = number(&desc.inner_angle, 0.0);
+            let (outer_angle, outer_span) = number(&desc.outer_angle, 45.0);
+            let (falloff, falloff_span) = number(&desc.falloff, 1.0);
+
+            if !(0.0..=180.0).contains(&outer_angle) {
+                return Err(source.error(outer_span, "outer_angle must be between 0 and 180 degrees"));
+            }
+
+            if !(0.0 <= inner_angle && inner_angle <= outer_angle) {
+                return Err(source.error(inner_span, "inner_angle must be between 0 and outer_angle"));
+            }
+
+            if !(falloff > 0.0 && falloff.is_finite()) {
+                return Err(source.error(falloff_span, "a spot light's falloff must be positive"));
+            }
+
+            Light::Spot {
+                position: vector("position", &desc.position)?,
+                direction: direction()?,
+                intensity: color("intensity", &desc.intensity)?,
+                inner_angle: inner_angle.to_radians(),
+                outer_angle: outer_angle.to_radians(),
+                falloff: falloff
+            }
+        }
+        LightKindDesc::Directional => {
+            let (angular_diameter, span) = number(&desc.angular_diameter, 0.0);
+            if !(0.0..180.0).contains(&angular_diameter) {
+                return Err(source.error(span, "a directional light's angular_diameter must be between 0 and 180 degrees"));
+            }
+
+            Light::Directional {
+                direction: direction()?,
+                irradiance: color("irradiance", &desc.irradiance)?,
+                angular_diameter: angular_diameter.to_radians()
+            }
+        }
+    };
+
+    scene.lights.push(light);
+    return Ok(());
+}
+
+
+/// Emission, intensity or irradiance, which the integrator can't make sense of below zero
+fn load_color(source: &Source, name: &str, value: &Spanned<[f32; 3]>) -> Result<Vec3, SceneError> {
+    if !value.get_ref().iter().all(|component| *component >= 0.0 && component.is_finite()) {
+        return Err(source.error(value.span(), format!("{name} can't be negative or infinite")));
+    }
+
+    return Ok(Vec3::from(*value.get_ref()));
+}
+
+
+fn load_camera(source: &Source, desc: &CameraDesc) -> Result<Camera, SceneError> {
+    let mut camera = Camera {
+        position: Vec3::from(desc.position),
+        look_at: Vec3::from(desc.look_at),
+        up: desc.up.map_or(Vec3::Y, Vec3::from),
+        ..Default::default()
+    };
+
+    let projection = desc.projection.as_ref().map_or(ProjectionDesc::Perspective, |projection| *projection.get_ref());
+    let perspective = projection == ProjectionDesc::Perspective;
+
+    // most keys only mean something to some projections
+    let keys = [
+        ("fov", desc.fov.as_ref().map(Spanned::span), perspective || projection == ProjectionDesc::Fisheye),
+        ("height", desc.height.as_ref().map(Spanned::span), projection == ProjectionDesc::Orthographic),
+        ("ipd", desc.ipd.as_ref().map(Spanned::span), projection == ProjectionDesc::Equirectangular),
+        ("focal_length", desc.focal_length.as_ref().map(Spanned::span), perspective),
+        ("sensor_height", desc.sensor_height.as_ref().map(Spanned::span), perspective),
+        ("f_stop", desc.f_stop.as_ref().map(Spanned::span), perspective),
+        ("focus_distance", desc.focus_distance.as_ref().map(Spanned::span), perspective),
+        ("blades", desc.blades.as_ref().map(Spanned::span), perspective),
+        ("blade_rotation", desc.blade_rotation.as_ref().map(Spanned::span), perspective),
+    ];
+
+    for (name, span, applies) in keys {
+        if let Some(span) = span && !applies {
+            return Err(source.error(span, format!("{name} doesn't apply to this projection")));
+        }
+    }
+
+    let positive = |name: &str, value: &Option<Spanned<f32>>| -> Result<Option<f32>, SceneError> {
+        return match value {
+            Some(value) if !(*value.get_ref() > 0.0 && value.get_ref().is_finite()) => Err(source.error(value.span(), format!("{name} must be positive"))),
+            value => Ok(value.as_ref().map(|value| *value.get_ref())),
+        };
+    };
+
+    match projection {
+        ProjectionDesc::Perspective => {}
+        ProjectionDesc::Orthographic => {
+            let Some(height) = positive("height", &desc.height)? else {
+                return Err(source.error(desc.projection.as_ref().map_or(0..0, Spanned::span), "an orthographic camera needs a height"));
+            };
+
+            camera.projection = Projection::Orthographic { height: height };
+            return Ok(camera);
+        }
+        ProjectionDesc::Fisheye => {
+            let fov = positive("fov", &desc.fov)?.unwrap_or(180.0);
+            if fov > 360.0 {
+                return Err(source.error(desc.fov.as_ref().map_or(0..0, Spanned::span), "a fisheye fov can be 360 degrees at most"));
+            }
+
+            camera.projection = Projection::Fisheye { fov: fov };
+            return Ok(camera);
+        }
+        ProjectionDesc::Equirectangular => {
+            camera.projection = Projection::Equirectangular { ipd: positive("ipd", &desc.ipd)? };
+            return Ok(camera);
+        }
+    }
+
+    if let Some(fov) = &desc.fov {
+        if !(*fov.get_ref() > 0.0 && *fov.get_ref() < 180.0) {
+            return Err(source.error(fov.span(), "fov must be between 0 and 180 degrees"));
+        }
+
+        camera.fov = *fov.get_ref();
+    }
+
+    if let Some(sensor_height) = positive("sensor_height", &desc.sensor_height)? {
+        camera.sensor_height = sensor_height;
+    }
+
+    if let Some(focal_length) = positive("focal_length", &desc.focal_length)? {
+        if let Some(fov) = &desc.fov {
+            return Err(source.error(fov.span(), "a camera has either a fov or a focal_length"));
+        }
+
+        camera.set_focal_length(focal_length);
+    }
+
+    let Some(f_stop) = positive("f_stop", &desc.f_stop)? else {
+        // the lens settings mean nothing to a pinhole
+        let span = desc.focus_distance.as_ref().map(|value| value.span())
+            .or(desc.blade_rotation.as_ref().map(|value| value.span()))
+            .or(desc.blades.as_ref().map(|value| value.span()));
+
+        if let Some(span) = span {
+            return Err(source.error(span, "depth of field needs an f_stop"));
+        }
+
+        return Ok(camera);
+    };
+
+    let focus_distance = positive("focus_distance", &desc.focus_distance)?.unwrap_or_else(|| (camera.look_at - camera.position).length());
+
+    camera.lens = Some(ThinLens {
+        f_stop: f_stop,
+        focus_distance: focus_distance.max(1e-3),
+        blades: desc.blades.as_ref().map_or(0, |blades| *blades.get_ref()),
+        blade_rotation: desc.blade_rotation.as_ref().map_or(0.0, |rotation| *rotation.get_ref())
+    });
+
+    return Ok(camera);
+}
+
+
+fn load_material(source: &Source, material: &Spanned<MaterialDesc>) -> Result<Material, SceneError> {
+    let desc = material.get_ref();
+    let principled = desc.kind == MaterialKindDesc::Principled;
+
+    // most keys only mean something to some types
+    let keys = [
+        ("roughness", desc.roughness.as_ref().map(Spanned::span), desc.kind != MaterialKindDesc::Diffuse),
+        ("metal", desc.metal.as_ref().map(Spanned::span), desc.kind == MaterialKindDesc::Conductor),
+        ("eta", desc.eta.map(|_| material.span()), desc.kind == MaterialKindDesc::Conductor),
+        ("k", desc.k.map(|_| material.span()), desc.kind == MaterialKindDesc::Conductor),
+        ("ior", desc.ior.as_ref().map(Spanned::span), desc.kind == MaterialKindDesc::Dielectric || principled),
+        ("metallic", desc.metallic.as_ref().map(Spanned::span), principled),
+        ("specular", desc.specular.as_ref().map(Spanned::span), principled),
+        ("anisotropy", desc.anisotropy.as_ref().map(Spanned::span), principled),
+        ("sheen", desc.sheen.as_ref().map(Spanned::span), principled),
+        ("sheen_tint", desc.sheen_tint.as_ref().map(Spanned::span), principled),
+        ("clearcoat", desc.clearcoat.as_ref().map(Spanned::span), principled),
+        ("clearcoat_roughness", desc.clearcoat_roughness.as_ref().map(Spanned::span), principled),
+        ("transmission", desc.transmission.as_ref().map(Spanned::span), principled),
+        ("subsurface", desc.subsurface.as_ref().map(Spanned::span), principled),
+    ];
+
+    for (name, span, applies) in keys {
+        if let Some(span) = span && !applies {
+            return Err(source.error(span, format!("{name} doesn't apply to this type of material")));
+        }
+    }
+
+    let fraction = |name: &str, value: &Option<Spanned<f32>>, default: f32| -> Result<f32, SceneError> {
+        return match value {
+            Some(value) if !(0.0..=1.0).contains(value.get_ref()) => Err(source.error(value.span(), format!("{name} must be between 0 and 1"))),
+            Some(value) => Ok(*value.get_ref()),
+            None => Ok(default),
+        };
+    };
+
+    let ior = match &desc.ior {
+        Some(ior) if !(*ior.get_ref() > 0.0 && ior.get_ref().is_finite()) => return Err(source.error(ior.span(), "ior must be positive")),
+        Some(ior) => *ior.get_ref(),
+        None => 1.5,
+    };
+
+    // smooth by default, except for principled materials which follow Blender
+    let roughness = fraction("roughness", &desc.roughness, if principled { 0.5 } else { 0.0 })?;
+
+    let mut result = match desc.kind {
+        MaterialKindDesc::Diffuse => Material::diffuse(desc.albedo.map_or(Vec3::ZERO, Vec3::from)),
+        MaterialKindDesc::Conductor => {
+            let ior = match (&desc.metal, desc.eta, desc.k) {
+                (Some(metal), None, None) => match ComplexIor::preset(metal.get_ref()) {
+                    Some(ior) => ior,
+                    None => return Err(source.error(metal.span(), format!("unknown metal \"{}\", try gold, copper, aluminium or silver", metal.get_ref()))),
+                },
+                (None, Some(eta), Some(k)) => ComplexIor { eta: Vec3::from(eta), k: Vec3::from(k) },
+                _ => return Err(source.error(material.span(), "a conductor needs either a metal or both eta and k")),
+            };
+
+            Material::conductor(ior, roughness)
+        }
+        MaterialKindDesc::Dielectric => Material::dielectric(ior, roughness),
+        MaterialKindDesc::Principled => {
+            let defaults = Principled::default();
+
+            let mut base = Material::principled(Vec3::splat(0.8), fraction("metallic", &desc.metallic, 0.0)?, roughness);
+            base.bsdf = Bsdf::Principled(Principled {
+                specular: fraction("specular", &desc.specular, defaults.specular)?,
+                anisotropy: fraction("anisotropy", &desc.anisotropy, defaults.anisotropy)?,
+                sheen: fraction("sheen", &desc.sheen, defaults.sheen)?,
+                sheen_tint: fraction("sheen_tint", &desc.sheen_tint, defaults.sheen_tint)?,
+                clearcoat: fraction("clearcoat", &desc.clearcoat, defaults.clearcoat)?,
+                clearcoat_roughness: fraction("clearcoat_roughness", &desc.clearcoat_roughness, defaults.clearcoat_roughness)?,
+                transmission: fraction("transmission", &desc.transmission, defaults.transmission)?,
+                ior: ior,
+                subsurface: fraction("subsurface", &desc.subsurface, defaults.subsurface)?
+            });
+
+            base
+        }
+    };
+
+    if let Some(albedo) = desc.albedo {
+        result.albedo = Vec3::from(albedo);
+    }
+
+    if let Some(emission) = &desc.emission {
+        result.emission = load_color(source, "emission", emission)?;
+    }
+
+    return Ok(result);
+}
+
+
+fn load_render_settings(source: &Source, desc: &RenderDesc) -> Result<SceneRenderSettings, SceneError> {
+    for (name, value) in [("width", &desc.width), ("height", &desc.height), ("spp", &desc.spp)] {
+        if let Some(value) = value && *value.get_ref() == 0 {
+            return Err(source.error(value.span(), format!("{name} must be at least 1")));
+        }
+    }
+
+    return Ok(SceneRenderSettings {
+        width: desc.width.as_ref().map(|width| *width.get_ref()),
+        height: desc.height.as_ref().map(|height| *height.get_ref()),
+        samples_per_pixel: desc.spp.as_ref().map(|spp| *spp.get_ref()),
+        max_bounces: desc.max_bounces,
+        seed: desc.seed
+    });
+}
+
+
+/// Returns the camera of a glTF mesh, if it has one
+fn load_mesh(scene: &mut Scene, source: &Source, directory: &Path, mesh: &Spanned<MeshDesc>, material: Option<u32>) -> Result<Option<Camera>, SceneError> {
+    let desc = mesh.get_ref();
+
+    let is_obj = desc.file.as_ref().is_some_and(|file| has_extension(file.get_ref(), &["obj"]));
+    let is_gltf = desc.file.as_ref().is_some_and(|file| has_extension(file.get_ref(), &["gltf", "glb"]));
+
+    if desc.normals.is_some() && !is_obj {
+        return Err(source.error(mesh.span(), "normals can only be generated for OBJ meshes"));
+    }
+
+    if is_gltf {
+        if let Some(name) = &desc.material {
+            return Err(source.error(name.span(), "glTF meshes always use their own materials"));
+        }
+
+        if desc.positions.is_some() || desc.indices.is_some() {
+            return Err(source.error(mesh.span(), "a glTF mesh can't have inline positions or indices"));
+        }
+    }
+
+    // only OBJ and glTF files bring their own materials
+    let material = match material {
+        Some(material) => material,
+        None if is_obj || is_gltf => 0,
+        None => return Err(source.error(mesh.span(), "this mesh needs a material")),
+    };
+
+    match (&desc.positions, &desc.file) {
+        (Some(positions), None) => {
+            add_indexed_mesh(scene, source, positions, desc.indices.as_ref(), material)?;
+            return Ok(None);
+        }
+        (None, Some(file)) => {
+            if desc.indices.is_some() {
+                return Err(source.error(mesh.span(), "indices belong in the referenced mesh file"));
+            }
+
+            let path = directory.join(file.get_ref());
+
+            if is_gltf {
+                return scene.load_gltf(&path);
+            }
+
+            if is_obj {
+                let options = ObjOptions {
+                    normals: match desc.normals {
+                        Some(NormalsDesc::Flat) => GeneratedNormals::Flat,
+                        Some(NormalsDesc::Smooth) | None => GeneratedNormals::Smooth,
+                    },
+                    material: desc.material.as_ref().map(|_| material),
+                };
+
+                scene.load_obj(&path, &options)?;
+                return Ok(None);
+            }
+
+            let mesh_source = Source::read(&path)?;
+            let mesh_desc: MeshFileDesc = mesh_source.parse()?;
+            add_indexed_mesh(scene, &mesh_source, &mesh_desc.positions, mesh_desc.indices.as_ref(), material)?;
+            return Ok(None);
+        }
+        (Some(_), Some(_)) => {
+            return Err(source.error(mesh.span(), "a mesh needs either inline positions or a file, not both"));
+        }
+        (None, None) => {
+            return Err(source.error(mesh.span(), "a mesh needs either inline positions or a file"));
+        }
+    }
+}
+
+
+fn add_indexed_mesh(scene: &mut Scene, source: &Source, positions: &Spanned<Vec<[f32; 3]>>, indices: Option<&Spanned<Vec<[u32; 3]>>>, material: u32) -> Result<(), SceneError> {
+    let span = positions.span();
+    let positions: Vec<Vec3> = positions.get_ref().iter().map(|&position| Vec3::from(position)).collect();
+
+    let Some(indices) = indices else {
+        if !positions.len().is_multiple_of(3) {
+            return Err(source.error(span, format!("{} positions don't make whole triangles, add indices", positions.len())));
+        }
+
+        for triangle in positions.chunks_exact(3) {
+            scene.add_triangle(triangle[0], triangle[1], triangle[2], material);
+        }
+
+        return Ok(());
+    };
+
+    for triangle in indices.get_ref() {
+        if let Some(&index) = triangle.iter().find(|&&index| index as usize >= positions.len()) {
+            return Err(source.error(
+                indices.span(),
+                format!("index {index} is out of range for {} positions", positions.len())
+            ));
+        }
+
+        scene.add_triangle(positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize], material);
+    }
+
+    return Ok(());
+}
+
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    return path.extension().is_some_and(|extension| {
+        extensions.iter().any(|wanted| extension.eq_ignore_ascii_case(wanted))
+    });
+}
+
+
+/// A camera looking down -z at the whole scene, for glTF files without one of their own
+fn frame(scene: &Scene) -> Camera {
+    let mut camera = Camera::default();
+
+    let mut min = Vec3::splat(f32::INFINITY);
+    let mut max = Vec3::splat(f32::NEG_INFINITY);
+    let mut grow = |point: Vec3| {
+        min = min.min(point);
+        max = max.max(point);
+    };
+
+    for triangle in &scene.triangles {
+        triangle.vertices.into_iter().for_each(&mut grow);
+    }
+
+    for instance in &scene.instances {
+        for triangle in &scene.meshes[instance.mesh as usize].triangles {
+            triangle.vertices.into_iter().for_each(|vertex| grow(instance.transform.transform_point(vertex)));
+        }
+    }
+
+    if min.x > max.x {
+        return camera;
+    }
+
+    let center = (min + max) * 0.5;
+    let radius = (max - min).length() * 0.5;
+    let distance = radius / (camera.fov.to_radians() / 2.0).sin();
+
+    camera.position = center + Vec3::new(0.0, 0.0, distance.max(1e-3));
+    camera.look_at = center;
+    return camera;
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    const CAMERA: &str = "[camera]\nposition = [0.0, 0.0, 1.0]\nlook_at = [0.0, 0.0, 0.0]\n";
+
+
+    fn load(text: &str) -> Result<Scene, SceneError> {
+        let source = Source { path: PathBuf::from("test.toml"), text: text.to_string() };
+        return load_scene(&source, Path::new(""));
+    }
+
+
+    /// Line, column and message of an invalid file
+    fn invalid(text: &str) -> (usize, usize, String) {
+        return match load(text) {
+            Err(SceneError::Invalid { line, column, message, .. }) => (line, column, message),
+            Err(err) => panic!("expected an invalid file, got {err}"),
+            Ok(_) => panic!("expected an invalid file, it loaded"),
+        };
+    }
+
+
+    #[test]
+    fn error_lines_and_columns_start_at_one() {
+        let source = Source { path: PathBuf::from("test.toml"), text: "ab\ncdé\nf".to_string() };
+
+        let at = |offset: usize| match source.error(offset..offset, "") {
+            SceneError::Invalid { line, column, .. } => (line, column),
+            _ => unreachable!(),
+        };
+
+        assert_eq!(at(0), (1, 1));
+        assert_eq!(at(1), (1, 2));
+        assert_eq!(at(3), (2, 1));
+        // columns count characters, not bytes
+        assert_eq!(at(7), (2, 4));
+        assert_eq!(at(8), (3, 1));
+        assert_eq!(at(100), (3, 2));
+    }
+
+
+    #[test]
+    fn loads_a_minimal_scene() {
+        let scene = load(&format!("version = 1\n{CAMERA}")).unwrap();
+        assert_eq!(scene.camera.position, Vec3::new(0.0, 0.0, 1.0));
+    }
+
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let (line, column, message) = invalid(&format!("version = {}\n{CAMERA}", FORMAT_VERSION + 1));
+        assert_eq!((line, column), (1, 11));
+        assert!(message.contains("newer"), "{message}");
+
+        let (line, column, message) = invalid(&format!("\nversion = 0\n{CAMERA}"));
+        assert_eq!((line, column), (2, 11));
+        assert!(message.contains("start at 1"), "{message}");
+
+        let (_, _, message) = invalid(CAMERA);
+        assert!(message.contains("version"), "{message}");
+    }
+
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}fow = 40.0\n"));
+        assert_eq!((line, column), (5, 1));
+        assert!(message.contains("fow"), "{message}");
+
+        let (line, column, _) = invalid(&format!("version = 1\n{CAMERA}\n[materials.red]\nalbedo = [1.0, 0.0, 0.0]\ncolour = 1.0\n"));
+        assert_eq!((line, column), (8, 1));
+    }
+
+
+    #[test]
+    fn rejects_keys_of_other_projections() {
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}projection = \"orthographic\"\nfov = 40.0\n"));
+        assert_eq!((line, column), (6, 7));
+        assert!(message.contains("fov"), "{message}");
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}projection = \"fisheye\"\nf_stop = 2.8\n"));
+        assert_eq!((line, column), (6, 10));
+        assert!(message.contains("f_stop"), "{message}");
+
+        load(&format!("version = 1\n{CAMERA}projection = \"orthographic\"\nheight = 2.0\n")).unwrap();
+    }
+
+
+    #[test]
+    fn rejects_keys_of_other_materials() {
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}\n[materials.white]\nroughness = 0.5\n"));
+        assert_eq!((line, column), (7, 13));
+        assert!(message.contains("roughness"), "{message}");
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}\n[materials.glass]\ntype = \"dielectric\"\nmetallic = 1.0\n"));
+        assert_eq!((line, column), (8, 12));
+        assert!(message.contains("metallic"), "{message}");
+
+        let (line, column, _) = invalid(&format!("version = 1\n{CAMERA}\n[materials.paint]\ntype = \"principled\"\nsheen = 2.0\n"));
+        assert_eq!((line, column), (8, 9));
+    }
+
+
+    #[test]
+    fn rejects_out_of_range_indices() {
+        let mesh = "[[meshes]]\nmaterial = \"white\"\npositions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]\n";
+        let materials = "[materials.white]\n";
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}{materials}{mesh}indices = [[0, 1, 3]]\n"));
+        assert_eq!((line, column), (9, 11));
+        assert!(message.contains("index 3"), "{message}");
+
+        let scene = load(&format!("version = 1\n{CAMERA}{materials}{mesh}indices = [[0, 1, 2]]\n")).unwrap();
+        assert_eq!(scene.triangles.len(), 1);
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}{materials}{mesh}\n[[meshes]]\nmaterial = \"black\"\n"));
+        assert_eq!((line, column), (11, 12));
+        assert!(message.contains("black"), "{message}");
+    }
+
+
+    #[test]
+    fn reports_errors_in_referenced_mesh_files() {
+        let directory = std::env::temp_dir().join(format!("scene-file-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("mesh.toml"), "\npositions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]]\n").unwrap();
+
+        let source = Source {
+            path: directory.join("scene.toml"),
+            text: format!("version = 1\n{CAMERA}[materials.white]\n[[meshes]]\nmaterial = \"white\"\nfile = \"mesh.toml\"\n")
+        };
+        let result = load_scene(&source, &directory);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        match result {
+            Err(SceneError::Invalid { path, line, column, message }) => {
+                assert_eq!(path, directory.join("mesh.toml"));
+                assert_eq!((line, column), (2, 13));
+                assert!(message.contains("2 positions"), "{message}");
+            }
+            Err(err) => panic!("expected an invalid file, got {err}"),
+            Ok(_) => panic!("expected an invalid file, it loaded"),
+        }
+    }
+
+
+    #[test]
+    fn sky_errors_point_at_their_keys() {
+        let scene = |sky: &str| format!("version = 1\n{CAMERA}\n[environment.sky]\n{sky}");
+
+        let (line, column, _) = invalid(&scene("turbidity = 1.0\nsun_elevation = 30.0\n"));
+        assert_eq!((line, column), (7, 13));
+
+        let (line, column, _) = invalid(&scene("sun_elevation = 30.0\nlatitude = 48.0\n"));
+        assert_eq!((line, column), (8, 12));
+
+        let (line, column, _) = invalid(&scene("sun_elevation = 95.0\n"));
+        assert_eq!((line, column), (7, 17));
+
+        let location = "latitude = 48.14\nlongitude = 11.58\ntime = 12:00:00\n";
+        // the TOML parser already knows how long months are
+        let (line, _, _) = invalid(&scene(&format!("{location}date = 2023-02-29\n")));
+        assert_eq!(line, 10);
+
+        let (line, column, _) = invalid(&scene(&format!("{location}date = 2024-02-29T10:00:00\n")));
+        assert_eq!((line, column), (10, 8));
+
+        let sky = load(&scene(&format!("{location}date = 2024-02-29\n"))).unwrap();
+        assert!(sky.environment.is_some());
+    }
+
+
+    #[test]
+    fn light_errors_point_at_their_keys() {
+        let light = |light: &str| format!("version = 1\n{CAMERA}\n[[lights]]\n{light}");
+        let spot = "type = \"spot\"\nposition = [0.0, 1.0, 0.0]\nintensity = [1.0, 1.0, 1.0]\n";
+
+        let (line, column, message) = invalid(&light(&format!("{spot}direction = [0.0, 0.0, 0.0]\n")));
+        assert_eq!((line, column), (10, 13));
+        assert!(message.contains("direction"), "{message}");
+
+        let (line, column, message) = invalid(&light(&format!("{spot}direction = [0.0, -1.0, 0.0]\ninner_angle = 50.0\n")));
+        assert_eq!((line, column), (11, 15));
+        assert!(message.contains("inner_angle"), "{message}");
+
+        let (line, column, _) = invalid(&light(&format!("{spot}direction = [0.0, -1.0, 0.0]\nfalloff = 0.0\n")));
+        assert_eq!((line, column), (11, 11));
+
+        let (line, column, message) = invalid(&light("type = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nirradiance = [1.0, 1.0, 1.0]\nangular_diameter = 180.0\n"));
+        assert_eq!((line, column), (10, 20));
+        assert!(message.contains("angular_diameter"), "{message}");
+
+        let (line, column, message) = invalid(&light("type = \"point\"\nposition = [0.0, 1.0, 0.0]\nintensity = [1.0, 1.0, 1.0]\nfalloff = 2.0\n"));
+        assert_eq!((line, column), (10, 11));
+        assert!(message.contains("falloff"), "{message}");
+
+        // a missing key can only point at the light
+        let (line, _, message) = invalid(&light("type = \"point\"\nposition = [0.0, 1.0, 0.0]\n"));
+        assert_eq!(line, 6);
+        assert!(message.contains("intensity"), "{message}");
+
+        let scene = load(&light(&format!("{spot}direction = [0.0, -1.0, 0.0]\n"))).unwrap();
+        assert_eq!(scene.lights.len(), 1);
+    }
+
+
+    #[test]
+    fn rejects_negative_and_infinite_emission() {
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}\n[[lights]]\ntype = \"point\"\nposition = [0.0, 1.0, 0.0]\nintensity = [1.0, -1.0, 1.0]\n"));
+        assert_eq!((line, column), (9, 13));
+        assert!(message.contains("intensity"), "{message}");
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}\n[[lights]]\ntype = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nirradiance = [inf, 1.0, 1.0]\n"));
+        assert_eq!((line, column), (9, 14));
+        assert!(message.contains("irradiance"), "{message}");
+
+        let quad = "type = \"quad\"\ncorner = [0.0, 0.0, 0.0]\nedge_a = [1.0, 0.0, 0.0]\nedge_b = [0.0, 0.0, 1.0]\n";
+        let (line, column, _) = invalid(&format!("version = 1\n{CAMERA}\n[[lights]]\n{quad}emission = [nan, 1.0, 1.0]\n"));
+        assert_eq!((line, column), (11, 12));
+
+        let (line, column, message) = invalid(&format!("version = 1\n{CAMERA}\n[materials.lamp]\nemission = [-5.0, 5.0, 5.0]\n"));
+        assert_eq!((line, column), (7, 12));
+        assert!(message.contains("emission"), "{message}");
+    }
+}
@@ -0,0 +1,421 @@
+use std::path::Path;
+
+use ::gltf::buffer::Data as BufferData;
+use ::gltf::camera::Projection;
+use ::gltf::image::{Data as ImageData, Format};
+use ::gltf::khr_lights_punctual::Kind;
+use ::gltf::mesh::Mode;
+use ::gltf::Node;
+
+use crate::math::{Mat4, Vec3};
+use super::{Bsdf, Camera, Light, Material, Mesh, Principled, Projection as CameraProjection, Scene, SceneError, Texture, Triangle};
+
+
+
+
+/// State shared by every node while walking the hierarchy
+struct Import<'a> {
+    path: &'a Path,
+    buffers: &'a [BufferData],
+    /// Where the file's materials start in Scene::materials
+    material_offset: u32,
+    default_material: Option<u32>,
+    /// Scene::meshes index of every glTF mesh loaded so far, so nodes sharing a mesh become instances of it
+    meshes: Vec<Option<u32>>,
+    camera: Option<Camera>,
+}
+
+
+
+
+impl Scene {
+    /// Appends the default scene of a glTF 2.0 file (.gltf or .glb): every mesh once plus an instance of it per node,
+    /// metallic-roughness materials, textures and KHR_lights_punctual lights.
+    /// Returns the first camera in the file, if there is one.
+    pub fn load_gltf(&mut self, path: impl AsRef<Path>) -> Result<Option<Camera>, SceneError> {
+        let path = path.as_ref();
+
+        let (document, buffers, images) = ::gltf::import(path).map_err(|err| SceneError::Import {
+            path: path.to_path_buf(),
+            message: err.to_string()
+        })?;
+
+        let Some(gltf_scene) = document.default_scene().or_else(|| document.scenes().next()) else {
+            return Err(SceneError::Import { path: path.to_path_buf(), message: "the file has no scenes".to_string() });
+        };
+
+        let texture_offset = self.textures.len() as u32;
+        self.textures.extend(images.iter().map(convert_image));
+
+        let material_offset = self.materials.len() as u32;
+        for material in document.materials() {
+            self.add_material(convert_material(&material, texture_offset));
+        }
+
+        let mut import = Import {
+            path: path,
+            buffers: &buffers,
+            material_offset: material_offset,
+            default_material: None,
+            meshes: vec![None; document.meshes().len()],
+            camera: None,
+        };
+
+        for node in gltf_scene.nodes() {
+            self.load_node(&mut import, &node, Mat4::IDENTITY)?;
+        }
+
+        return Ok(import.camera);
+    }
+
+
+    fn load_node(&mut self, import: &mut Import, node: &Node, parent: Mat4) -> Result<(), SceneError> {
+        let transform = parent * Mat4::from_columns(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            let index = match import.meshes[mesh.index()] {
+                Some(index) => index,
+                None => {
+                    let index = self.load_mesh(import, &mesh)?;
+                    import.meshes[mesh.index()] = Some(index);
+                    index
+                }
+            };
+
+            // zero scale is a common way to hide things, and can't be traced anyway
+            if self.meshes[index as usize].triangles.is_empty() || transform.affine_inverse().is_none() {
+                log::debug!("{}: skipping an empty or flattened instance of mesh {}", import.path.display(), mesh.index());
+            } else {
+                self.add_instance(index, transform, None);
+            }
+        }
+
+        // only the first camera is used, the renderer has just the one
+        if let Some(camera) = node.camera() && import.camera.is_none() {
+            let position = transform.transform_point(Vec3::ZERO);
+            let mut imported = Camera {
+                position: position,
+                look_at: position + transform.transform_vector(Vec3::new(0.0, 0.0, -1.0)).normalize(),
+                up: transform.transform_vector(Vec3::Y).normalize(),
+                ..Default::default()
+            };
+
+            match camera.projection() {
+                Projection::Perspective(perspective) => imported.fov = perspective.yfov().to_degrees(),
+                // ymag is half the height, the aspect ratio comes from the image like it does for perspective cameras
+                Projection::Orthographic(orthographic) => imported.projection = CameraProjection::Orthographic { height: 2.0 * orthographic.ymag() },
+            }
+
+            import.camera = Some(imported);
+        }
+
+        if let Some(light) = node.light() {
+            // photometric units are taken as is, the scenes are unitless anyway
+            let intensity = Vec3::from(light.color()) * light.intensity();
+            let position = transform.transform_point(Vec3::ZERO);
+            let direction = transform.transform_vector(Vec3::new(0.0, 0.0, -1.0)).normalize();
+
+            self.lights.push(match light.kind() {
+                Kind::Point => Light::Point { position: position, intensity: intensity },
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => Light::Spot {
+                    position: position,
+                    direction: direction,
+                    intensity: intensity,
+                    inner_angle: inner_cone_angle,
+                    outer_angle: outer_cone_angle,
+                    falloff: 1.0
+                },
+                Kind::Directional => Light::Directional { direction: direction, irradiance: intensity, angular_diameter: 0.0 },
+            });
+        }
+
+        for child in node.children() {
+            self.load_node(import, &child, transform)?;
+        }
+
+        return Ok(());
+    }
+
+
+    /// Adds every triangle primitive of a glTF mesh as one Scene mesh, in the mesh's own space
+    fn load_mesh(&mut self, import: &mut Import, mesh: &::gltf::Mesh) -> Result<u32, SceneError> {
+        let mut triangles = Vec::new();
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                log::warn!("{}: skipping a {:?} primitive of mesh {}, only triangles are supported", import.path.display(), primitive.mode(), mesh.index());
+                continue;
+            }
+
+            let material = match primitive.material().index() {
+                Some(index) => import.material_offset + index as u32,
+                None => *import.default_material.get_or_insert_with(|| self.add_material(Material::default())),
+            };
+
+            load_primitive(import, &primitive, material, &mut triangles)?;
+        }
+
+        return Ok(self.add_mesh(Mesh { triangles: triangles }));
+    }
+}
+
+
+fn load_primitive(import: &Import, primitive: &::gltf::Primitive, material: u32, triangles: &mut Vec<Triangle>) -> Result<(), SceneError> {
+    let reader = primitive.reader(|buffer| Some(&import.buffers[buffer.index()]));
+
+    let Some(positions) = reader.read_positions() else {
+        return Ok(());
+    };
+
+    let positions: Vec<Vec3> = positions.map(Vec3::from).collect();
+
+    // zero length normals are left as zero and replaced by the face normal below
+    let normals: Vec<Vec3> = reader.read_normals()
+        .map(|normals| normals.map(Vec3::from).map(|normal| if normal.length() > 0.0 { normal.normalize() } else { Vec3::ZERO }).collect())
+        .unwrap_or_default();
+
+    // glTF already puts the uv origin in the top left
+    let uvs: Vec<[f32; 2]> = reader.read_tex_coords(0)
+        .map(|uvs| uvs.into_f32().collect())
+        .unwrap_or_default();
+
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+
+    if let Some(&index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+        return Err(SceneError::Import {
+            path: import.path.to_path_buf(),
+            message: format!("index {index} is out of range for {} positions", positions.len())
+        });
+    }
+
+    for face in indices.chunks_exact(3) {
+        let face = [face[0] as usize, face[1] as usize, face[2] as usize];
+        let mut triangle = Triangle::new(positions[face[0]], positions[face[1]], positions[face[2]], material);
+
+        // without normals the spec asks for flat shading, which is what Triangle::new gives
+        if normals.len() == positions.len() {
+            let face_normal = triangle.normal();
+            triangle.normals = face.map(|index| if normals[index] == Vec3::ZERO { face_normal } else { normals[index] });
+        }
+
+        if uvs.len() == positions.len() {
+            triangle.uvs = face.map(|index| uvs[index]);
+        }
+
+        triangles.push(triangle);
+    }
+
+    return Ok(());
+}
+
+
+fn convert_material(material: &::gltf::Material, texture_offset: u32) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let texture = |info: Option<::gltf::texture::Info>| info.map(|info| texture_offset + info.texture().source().index() as u32);
+
+    let base_color = pbr.base_color_factor();
+
+    // KHR_materials_specular scales the reflectance the IOR gives, where the principled specular of 0.5 is 4%
+    let ior = material.ior().unwrap_or(1.5);
+    let reflectance = ((ior - 1.0) / (ior + 1.0)).powi(2);
+    let specular_factor = material.specular().map_or(1.0, |specular| specular.specular_factor());
+
+    let principled = Principled {
+        specular: (reflectance / 0.08 * specular_factor).min(1.0),
+        transmission: material.transmission().map_or(0.0, |transmission| transmission.transmission_factor()),
+        ior: ior,
+        ..Default::default()
+    };
+
+    return Material {
+        bsdf: Bsdf::Principled(principled),
+        albedo: Vec3::new(base_color[0], base_color[1], base_color[2]),
+        albedo_texture: texture(pbr.base_color_texture()),
+        emission: Vec3::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0),
+        emission_texture: texture(material.emissive_texture()),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        metallic_roughness_texture: texture(pbr.metallic_roughness_texture())
+    };
+}
+
+
+/// Widens or narrows any decoded image to 8 bit RGBA, grey images are spread over RGB
+fn convert_image(image: &ImageData) -> Texture {
+    let channels = match image.format {
+        Format::R8 | Format::R16 => 1,
+        Format::R8G8 | Format::R16G16 => 2,
+        Format::R8G8B8 | Format::R16G16B16 | Format::R32G32B32FLOAT => 3,
+        Format::R8G8B8A8 | Format::R16G16B16A16 | Format::R32G32B32A32FLOAT => 4,
+    };
+
+    // the decoder hands out wider channels in native byte order
+    let values: Vec<u8> = match image.format {
+        Format::R8 | Format::R8G8 | Format::R8G8B8 | Format::R8G8B8A8 => image.pixels.clone(),
+        Format::R16 | Format::R16G16 | Format::R16G16B16 | Format::R16G16B16A16 => image.pixels.chunks_exact(2)
+            .map(|value| (u16::from_ne_bytes([value[0], value[1]]) >> 8) as u8)
+            .collect(),
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => image.pixels.chunks_exact(4)
+            .map(|value| (f32::from_ne_bytes([value[0], value[1], value[2], value[3]]).clamp(0.0, 1.0) * 255.0 + 0.5) as u8)
+            .collect(),
+    };
+
+    let pixels = values.chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [grey] => [grey, grey, grey, 255],
+            [grey, alpha] => [grey, grey, grey, alpha],
+            [r, g, b] => [r, g, b, 255],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!(),
+        })
+        .collect();
+
+    return Texture {
+        width: image.width,
+        height: image.height,
+        pixels: pixels
+    };
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// One triangle in the xy plane whose first normal is zero, used by two nodes and a zero scale one,
+    /// with two cameras and a spot light
+    const TEST_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "extensionsUsed": ["KHR_lights_punctual"],
+        "extensions": {
+            "KHR_lights_punctual": {
+                "lights": [{ "type": "spot", "color": [1.0, 0.5, 0.25], "intensity": 2.0, "spot": { "innerConeAngle": 0.2, "outerConeAngle": 0.4 } }]
+            }
+        },
+        "buffers": [{
+            "byteLength": 72,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/"
+        }],
+        "bufferViews": [{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }, { "buffer": 0, "byteOffset": 36, "byteLength": 36 }],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0] },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }
+        ],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0, "NORMAL": 1 } }] }],
+        "cameras": [
+            { "type": "perspective", "perspective": { "yfov": 0.5, "znear": 0.1 } },
+            { "type": "perspective", "perspective": { "yfov": 1.0, "znear": 0.1 } }
+        ],
+        "nodes": [
+            { "mesh": 0, "translation": [1.0, 0.0, 0.0] },
+            { "translation": [0.0, 2.0, 0.0], "children": [6] },
+            { "mesh": 0, "scale": [0.0, 0.0, 0.0] },
+            { "camera": 0, "translation": [0.0, 1.0, 5.0], "rotation": [0.0, 0.70710677, 0.0, 0.70710677] },
+            { "translation": [0.0, 3.0, 0.0], "extensions": { "KHR_lights_punctual": { "light": 0 } } },
+            { "camera": 1 },
+            { "mesh": 0, "translation": [0.0, 0.0, 3.0] }
+        ],
+        "scenes": [{ "nodes": [0, 1, 2, 3, 4, 5] }],
+        "scene": 0
+    }"#;
+
+
+    fn load_test_gltf(name: &str) -> (Scene, Option<Camera>) {
+        let path = std::env::temp_dir().join(format!("gltf-{name}-test-{}.gltf", std::process::id()));
+        std::fs::write(&path, TEST_GLTF).unwrap();
+
+        let mut scene = Scene::default();
+        let result = scene.load_gltf(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let camera = result.unwrap();
+        return (scene, camera);
+    }
+
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-5, "{a:?} != {b:?}");
+    }
+
+
+    #[test]
+    fn nodes_sharing_a_mesh_become_instances_of_it() {
+        let (scene, _) = load_test_gltf("instances");
+
+        // the zero scale node is skipped
+        assert_eq!(scene.meshes.len(), 1);
+        assert_eq!(scene.instances.len(), 2);
+        assert!(scene.instances.iter().all(|instance| instance.mesh == 0 && instance.material.is_none()));
+
+        // the child's translation is applied after its parent's
+        assert_close(scene.instances[0].transform.transform_point(Vec3::ZERO), Vec3::new(1.0, 0.0, 0.0));
+        assert_close(scene.instances[1].transform.transform_point(Vec3::ZERO), Vec3::new(0.0, 2.0, 3.0));
+    }
+
+
+    #[test]
+    fn zero_normals_in_the_file_fall_back_to_the_face_normal() {
+        let (scene, _) = load_test_gltf("normals");
+
+        let triangle = &scene.meshes[0].triangles[0];
+        assert_eq!(triangle.normals, [Vec3::new(0.0, 0.0, 1.0); 3]);
+        assert_eq!(scene.materials[triangle.material as usize].albedo, Material::default().albedo);
+    }
+
+
+    #[test]
+    fn the_first_camera_is_used() {
+        let (_, camera) = load_test_gltf("camera");
+        let camera = camera.unwrap();
+
+        assert!((camera.fov - 0.5f32.to_degrees()).abs() < 1e-4);
+        assert_close(camera.position, Vec3::new(0.0, 1.0, 5.0));
+
+        // a quarter turn around y takes -z to -x
+        assert_close(camera.look_at, Vec3::new(-1.0, 1.0, 5.0));
+        assert_close(camera.up, Vec3::Y);
+    }
+
+
+    #[test]
+    fn spot_lights_keep_their_cone_angles() {
+        let (scene, _) = load_test_gltf("lights");
+
+        let [Light::Spot { position, direction, intensity, inner_angle, outer_angle, .. }] = scene.lights[..] else {
+            panic!("expected one spot light, got {:?}", scene.lights);
+        };
+
+        assert_close(position, Vec3::new(0.0, 3.0, 0.0));
+        assert_close(direction, Vec3::new(0.0, 0.0, -1.0));
+        assert_close(intensity, Vec3::new(2.0, 1.0, 0.5));
+        assert_eq!((inner_angle, outer_angle), (0.2, 0.4));
+    }
+
+
+    #[test]
+    fn sixteen_bit_and_grey_alpha_images_become_rgba8() {
+        let r16 = ImageData {
+            pixels: [0xff00u16, 0x1234].iter().flat_map(|value| value.to_ne_bytes()).collect(),
+            format: Format::R16,
+            width: 2,
+            height: 1
+        };
+        assert_eq!(convert_image(&r16).pixels, [0xff, 0xff, 0xff, 255, 0x12, 0x12, 0x12, 255]);
+
+        let grey_alpha = ImageData {
+            pixels: vec![10, 20],
+            format: Format::R8G8,
+            width: 1,
+            height: 1
+        };
+        let texture = convert_image(&grey_alpha);
+        assert_eq!((texture.width, texture.height), (1, 1));
+        assert_eq!(texture.pixels, [10, 10, 10, 20]);
+    }
+}
@@ -0,0 +1,531 @@
+use std::path::Path;
+
+use crate::math::{Mat4, Vec3};
+use crate::renderer::RenderSettings;
+
+mod file;
+mod gltf;
+mod obj;
+mod sky;
+
+pub use file::{FORMAT_VERSION, SceneError};
+pub use obj::{GeneratedNormals, ObjOptions};
+pub use sky::{Sky, SunPosition};
+
+
+
+
+/// A pinhole camera, or a thin lens one with depth of field. The aspect ratio comes from the image
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub projection: Projection,
+    /// Vertical field of view in degrees, for the perspective projection
+    pub fov: f32,
+    /// Height of the sensor in millimetres, which with the fov gives the focal length. 24 is full frame
+    pub sensor_height: f32,
+    /// None is a pinhole, where everything is in focus
+    pub lens: Option<ThinLens>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        return Self {
+            position: Vec3::new(0.0, 0.0, 1.0),
+            look_at: Vec3::ZERO,
+            up: Vec3::Y,
+            projection: Projection::default(),
+            fov: 40.0,
+            sensor_height: 24.0,
+            lens: None
+        };
+    }
+}
+
+
+/// How the camera maps directions onto the image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Projection {
+    /// Straight lines stay straight, the only one the lens applies to
+    #[default]
+    Perspective,
+    /// Parallel rays, for elevations and plans without any foreshortening
+    Orthographic {
+        /// Of the image, in scene units
+        height: f32,
+    },
+    /// Equidistant, the angle from the view direction grows linearly with the distance from the image centre
+    Fisheye {
+        /// In degrees across the image circle, which touches the top and bottom of the image
+        fov: f32,
+    },
+    /// Latitude and longitude, the full sphere around the camera with the view direction in the middle
+    Equirectangular {
+        /// Interpupillary distance in scene units for omnidirectional stereo, with the left eye
+        /// in the top half of the image and the right one below. None renders a single eye
+        ipd: Option<f32>,
+    },
+}
+
+impl Camera {
+    /// In millimetres, the distance from the lens to the sensor that gives the fov
+    pub fn focal_length(&self) -> f32 {
+        return self.sensor_height / 2.0 / (self.fov.to_radians() / 2.0).tan();
+    }
+
+
+    /// Sets the fov a lens of this focal length in millimetres has on the sensor
+    pub fn set_focal_length(&mut self, focal_length: f32) {
+        self.fov = (2.0 * (self.sensor_height / 2.0 / focal_length).atan()).to_degrees();
+    }
+
+
+    /// Radius of the aperture in scene units, which are taken to be metres
+    pub fn aperture_radius(&self) -> f32 {
+        return self.lens.map_or(0.0, |lens| self.focal_length() / lens.f_stop / 2.0 / 1000.0);
+    }
+}
+
+
+/// Depth of field from a lens of no thickness
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThinLens {
+    /// The focal length over the diameter of the aperture, smaller blurs more
+    pub f_stop: f32,
+    /// Distance along the view direction that is in focus
+    pub focus_distance: f32,
+    /// Aperture blades, which give bokeh their polygon shape. Fewer than 3 is a round aperture
+    pub blades: u32,
+    /// Turns the polygon, in degrees
+    pub blade_rotation: f32,
+}
+
+impl Default for ThinLens {
+    fn default() -> Self {
+        return Self {
+            f_stop: 2.8,
+            focus_distance: 1.0,
+            blades: 0,
+            blade_rotation: 0.0
+        };
+    }
+}
+
+
+/// Textures multiply the matching constant, indices point into Scene::textures
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub bsdf: Bsdf,
+    /// The colour of diffuse surfaces, and a tint for the others
+    pub albedo: Vec3,
+    /// sRGB encoded
+    pub albedo_texture: Option<u32>,
+    pub emission: Vec3,
+    /// sRGB encoded
+    pub emission_texture: Option<u32>,
+    pub metallic: f32,
+    /// Perceptual GGX roughness from 0 for a mirror to 1, the square of the microfacet alpha
+    pub roughness: f32,
+    /// Linear, roughness in green and metallic in blue like glTF
+    pub metallic_roughness_texture: Option<u32>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        return Self {
+            bsdf: Bsdf::default(),
+            albedo: Vec3::splat(0.8),
+            albedo_texture: None,
+            emission: Vec3::ZERO,
+            emission_texture: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            metallic_roughness_texture: None
+        };
+    }
+}
+
+impl Material {
+    pub fn diffuse(albedo: Vec3) -> Self {
+        return Self { albedo: albedo, ..Default::default() };
+    }
+
+    pub fn emissive(emission: Vec3) -> Self {
+        return Self { albedo: Vec3::ZERO, emission: emission, ..Default::default() };
+    }
+
+    pub fn conductor(ior: ComplexIor, roughness: f32) -> Self {
+        return Self { bsdf: Bsdf::Conductor(ior), albedo: Vec3::ONE, roughness: roughness, ..Default::default() };
+    }
+
+    pub fn dielectric(ior: f32, roughness: f32) -> Self {
+        return Self { bsdf: Bsdf::Dielectric { ior: ior }, albedo: Vec3::ONE, roughness: roughness, ..Default::default() };
+    }
+
+    /// A principled material with its default parameters, the albedo being the base colour
+    pub fn principled(base_color: Vec3, metallic: f32, roughness: f32) -> Self {
+        return Self {
+            bsdf: Bsdf::Principled(Principled::default()),
+            albedo: base_color,
+            metallic: metallic,
+            roughness: roughness,
+            ..Default::default()
+        };
+    }
+}
+
+
+/// How light scatters off a material
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Bsdf {
+    /// Lambertian, scattering the same amount in every direction
+    #[default]
+    Diffuse,
+    /// Metal, a GGX microfacet reflection with the Fresnel of a complex index of refraction
+    Conductor(ComplexIor),
+    /// Glass and water, reflecting and refracting through GGX microfacets, or a perfectly smooth surface at roughness 0
+    Dielectric {
+        /// Of the inside relative to the outside, 1.5 for glass
+        ior: f32,
+    },
+    /// Disney's principled BSDF as in Blender and Substance, a blend of all of the above plus sheen and a clear coat.
+    /// The albedo is the base colour, and Material::metallic and roughness apply
+    Principled(Principled),
+}
+
+
+/// The parameters of a principled material that aren't part of every Material. All of them go from 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Principled {
+    /// Reflectance of the non-metallic part at normal incidence, 0.5 is 4% like an IOR of 1.5
+    pub specular: f32,
+    /// Stretches highlights along the texture's u direction
+    pub anisotropy: f32,
+    /// A soft rim of light at grazing angles, for cloth
+    pub sheen: f32,
+    /// How much the sheen takes on the base colour instead of being white
+    pub sheen_tint: f32,
+    /// Strength of a second, colourless specular layer on top
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// Refracts through the surface like glass instead of scattering diffusely
+    pub transmission: f32,
+    /// Index of refraction for the transmission, not limited to 0 to 1
+    pub ior: f32,
+    /// Flattens the diffuse lobe like light scattering under the surface would, without actually going under it
+    pub subsurface: f32,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        return Self {
+            specular: 0.5,
+            anisotropy: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            transmission: 0.0,
+            ior: 1.5,
+            subsurface: 0.0
+        };
+    }
+}
+
+
+/// Complex index of refraction of a conductor, sampled at red, green and blue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ComplexIor {
+    pub eta: Vec3,
+    /// The extinction coefficient, how strongly the metal absorbs
+    pub k: Vec3,
+}
+
+impl ComplexIor {
+    pub const GOLD: Self = Self { eta: Vec3::new(0.143, 0.374, 1.442), k: Vec3::new(3.983, 2.385, 1.603) };
+    pub const COPPER: Self = Self { eta: Vec3::new(0.2, 0.924, 1.102), k: Vec3::new(3.912, 2.452, 2.142) };
+    pub const ALUMINIUM: Self = Self { eta: Vec3::new(1.657, 0.88, 0.521), k: Vec3::new(9.224, 6.27, 4.837) };
+    pub const SILVER: Self = Self { eta: Vec3::new(0.155, 0.117, 0.138), k: Vec3::new(4.828, 3.122, 2.147) };
+
+    /// One of the constants above by name, in lower case
+    pub fn preset(name: &str) -> Option<Self> {
+        return match name {
+            "gold" => Some(Self::GOLD),
+            "copper" => Some(Self::COPPER),
+            "aluminium" | "aluminum" => Some(Self::ALUMINIUM),
+            "silver" => Some(Self::SILVER),
+            _ => None,
+        };
+    }
+}
+
+
+/// An 8 bit RGBA image, rows from top to bottom
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+
+/// Radiance arriving from every direction around the scene, as an equirectangular image. The middle of the image
+/// looks along -z with +x to the right of it, and the top row is straight up
+#[derive(Clone, Debug)]
+pub struct EnvironmentMap {
+    pub width: u32,
+    pub height: u32,
+    /// Linear RGB, rows from top to bottom
+    pub pixels: Vec<f32>,
+    /// Around +y in radians, turning +x towards -z
+    pub rotation: f32,
+    /// Multiplies every pixel
+    pub intensity: f32,
+}
+
+impl EnvironmentMap {
+    /// Reads a Radiance .hdr or OpenEXR file, or any other image, unrotated and at its own intensity
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let image = image::open(path).map_err(|err| SceneError::Import { path: path.to_path_buf(), message: err.to_string() })?;
+        let image = image.into_rgb32f();
+
+        return Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.into_raw(),
+            rotation: 0.0,
+            intensity: 1.0
+        });
+    }
+}
+
+
+/// Lights that aren't made of geometry. Intensities are radiometric, already multiplied by the light color.
+/// Triangles with an emissive material are lights too, the renderer finds those itself
+#[derive(Clone, Debug)]
+pub enum Light {
+    Point {
+        position: Vec3,
+        /// Radiant intensity
+        intensity: Vec3,
+    },
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        /// Half angles in radians, full intensity inside the inner cone fading out to the outer one
+        inner_angle: f32,
+        outer_angle: f32,
+        /// Exponent of the fade between the cones, 1 is linear in the cosine like glTF and more is tighter
+        falloff: f32,
+    },
+    Directional {
+        /// The direction the light travels in
+        direction: Vec3,
+        irradiance: Vec3,
+        /// In radians, 0 casts hard shadows and the sun is about 0.0093
+        angular_diameter: f32,
+    },
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    /// Shading normals, interpolated across the face
+    pub normals: [Vec3; 3],
+    /// Texture coordinates with the origin in the top left corner of the texture
+    pub uvs: [[f32; 2]; 3],
+    /// Index into Scene::materials
+    pub material: u32,
+}
+
+impl Triangle {
+    /// A flat shaded triangle without texture coordinates
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: u32) -> Self {
+        let mut triangle = Self {
+            vertices: [v0, v1, v2],
+            normals: [Vec3::ZERO; 3],
+            uvs: [[0.0, 0.0]; 3],
+            material: material
+        };
+
+        triangle.normals = [triangle.normal(); 3];
+        return triangle;
+    }
+
+    /// The geometric normal, following the winding order. Zero for degenerate triangles
+    pub fn normal(&self) -> Vec3 {
+        let [v0, v1, v2] = self.vertices;
+        let normal = (v1 - v0).cross(v2 - v0);
+
+        if normal.length() > 0.0 {
+            return normal.normalize();
+        }
+
+        return Vec3::ZERO;
+    }
+}
+
+
+
+/// Geometry that can be placed any number of times by instances, in its own object space
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub triangles: Vec<Triangle>,
+}
+
+
+/// One placement of a mesh. Instances share the mesh's triangles, so a thousand trees cost one tree of memory
+#[derive(Clone, Debug)]
+pub struct Instance {
+    /// Index into Scene::meshes
+    pub mesh: u32,
+    /// Object to world, must be affine and invertible
+    pub transform: Mat4,
+    /// Replaces the material of every triangle in the mesh
+    pub material: Option<u32>,
+}
+
+
+
+
+/// Render settings a scene asks for, anything left as None uses the caller's choice
+#[derive(Clone, Debug, Default)]
+pub struct SceneRenderSettings {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub samples_per_pixel: Option<u32>,
+    pub max_bounces: Option<u32>,
+    pub seed: Option<u32>,
+}
+
+impl SceneRenderSettings {
+    /// Overwrites the fields of settings this scene has an opinion on
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        settings.width = self.width.unwrap_or(settings.width);
+        settings.height = self.height.unwrap_or(settings.height);
+        settings.samples_per_pixel = self.samples_per_pixel.unwrap_or(settings.samples_per_pixel);
+        settings.max_bounces = self.max_bounces.unwrap_or(settings.max_bounces);
+        settings.seed = self.seed.unwrap_or(settings.seed);
+    }
+}
+
+
+
+
+/// Everything the renderer needs to know about what to draw
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub camera: Camera,
+    /// Triangles placed straight in world space, outside of any mesh
+    pub triangles: Vec<Triangle>,
+    pub meshes: Vec<Mesh>,
+    pub instances: Vec<Instance>,
+    pub materials: Vec<Material>,
+    pub textures: Vec<Texture>,
+    pub lights: Vec<Light>,
+    /// Radiance of rays that leave the scene
+    pub background: Vec3,
+    /// Replaces background when there is one
+    pub environment: Option<EnvironmentMap>,
+    /// The sun of the sky from set_sky, apart from lights so another sky replaces it
+    pub sun: Option<Light>,
+    /// Settings from the scene file, the renderer itself ignores these
+    pub render_settings: SceneRenderSettings,
+}
+
+
+impl Scene {
+    pub fn add_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        return (self.materials.len() - 1) as u32;
+    }
+
+
+    pub fn add_triangle(&mut self, v0: Vec3, v1: Vec3, v2: Vec3, material: u32) {
+        self.triangles.push(Triangle::new(v0, v1, v2, material));
+    }
+
+
+    /// Replaces the environment with the sky and the last sky's sun with its own
+    pub fn set_sky(&mut self, sky: &Sky) {
+        self.environment = Some(sky.environment_map());
+        self.sun = sky.sun();
+    }
+
+
+    /// The lights and then the sun
+    pub fn all_lights(&self) -> impl Iterator<Item = &Light> {
+        return self.lights.iter().chain(&self.sun);
+    }
+
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> u32 {
+        self.meshes.push(mesh);
+        return (self.meshes.len() - 1) as u32;
+    }
+
+
+    pub fn add_instance(&mut self, mesh: u32, transform: Mat4, material: Option<u32>) {
+        self.instances.push(Instance {
+            mesh: mesh,
+            transform: transform,
+            material: material
+        });
+    }
+
+
+    /// Two triangles spanning the parallelogram a, b, c, d (in winding order)
+    pub fn add_quad(&mut self, a: Vec3, b: Vec3, c: Vec3, d: Vec3, material: u32) {
+        self.add_triangle(a, b, c, material);
+        self.add_triangle(a, c, d, material);
+    }
+
+
+    /// The classic Cornell box: a 2x2x2 room open towards +z with a light in the ceiling
+    pub fn cornell_box() -> Self {
+        let mut scene = Scene {
+            camera: Camera {
+                position: Vec3::new(0.0, 1.0, 3.9),
+                look_at: Vec3::new(0.0, 1.0, 0.0),
+                up: Vec3::Y,
+                fov: 40.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let white = scene.add_material(Material::diffuse(Vec3::new(0.73, 0.73, 0.73)));
+        let red = scene.add_material(Material::diffuse(Vec3::new(0.65, 0.05, 0.05)));
+        let green = scene.add_material(Material::diffuse(Vec3::new(0.12, 0.45, 0.15)));
+        let light = scene.add_material(Material::emissive(Vec3::splat(15.0)));
+
+        let v = Vec3::new;
+
+        // floor, ceiling, back wall
+        scene.add_quad(v(-1.0, 0.0, -1.0), v(1.0, 0.0, -1.0), v(1.0, 0.0, 1.0), v(-1.0, 0.0, 1.0), white);
+        scene.add_quad(v(-1.0, 2.0, -1.0), v(-1.0, 2.0, 1.0), v(1.0, 2.0, 1.0), v(1.0, 2.0, -1.0), white);
+        scene.add_quad(v(-1.0, 0.0, -1.0), v(-1.0, 2.0, -1.0), v(1.0, 2.0, -1.0), v(1.0, 0.0, -1.0), white);
+
+        // left and right walls
+        scene.add_quad(v(-1.0, 0.0, -1.0), v(-1.0, 0.0, 1.0), v(-1.0, 2.0, 1.0), v(-1.0, 2.0, -1.0), red);
+        scene.add_quad(v(1.0, 0.0, -1.0), v(1.0, 2.0, -1.0), v(1.0, 2.0, 1.0), v(1.0, 0.0, 1.0), green);
+
+        // light, just below the ceiling so it doesn't z-fight
+        scene.add_quad(v(-0.25, 1.99, -0.25), v(0.25, 1.99, -0.25), v(0.25, 1.99, 0.25), v(-0.25, 1.99, 0.25), light);
+
+        // a short box standing on the floor
+        let (x0, x1, z0, z1, h) = (-0.6, -0.05, -0.6, -0.05, 1.2);
+        scene.add_quad(v(x0, h, z0), v(x1, h, z0), v(x1, h, z1), v(x0, h, z1), white);
+        scene.add_quad(v(x0, 0.0, z1), v(x1, 0.0, z1), v(x1, h, z1), v(x0, h, z1), white);
+        scene.add_quad(v(x0, 0.0, z0), v(x0, h, z0), v(x1, h, z0), v(x1, 0.0, z0), white);
+        scene.add_quad(v(x0, 0.0, z0), v(x0, 0.0, z1), v(x0, h, z1), v(x0, h, z0), white);
+        scene.add_quad(v(x1, 0.0, z0), v(x1, h, z0), v(x1, h, z1), v(x1, 0.0, z1), white);
+
+        return scene;
+    }
+}
@@ -0,0 +1,213 @@
+use std::path::Path;
+
+use crate::math::Vec3;
+use super::{Material, Scene, SceneError, Triangle};
+
+
+
+
+/// What to do about meshes that come without vertex normals
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GeneratedNormals {
+    /// Average the normals of the faces around each vertex, weighted by area
+    #[default]
+    Smooth,
+    /// Use every face's own normal
+    Flat,
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct ObjOptions {
+    pub normals: GeneratedNormals,
+    /// Use this material for every face instead of the ones from the MTL file
+    pub material: Option<u32>,
+}
+
+
+
+
+impl Scene {
+    /// Appends the triangles of a Wavefront OBJ file, plus the materials of its MTL library.
+    /// Polygons are fan triangulated and negative (relative) indices are resolved.
+    pub fn load_obj(&mut self, path: impl AsRef<Path>, options: &ObjOptions) -> Result<(), SceneError> {
+        let path = path.as_ref();
+
+        let load_options = tobj::LoadOptions {
+            single_index: true,
+            triangulate: true,
+            ignore_points: true,
+            ignore_lines: true,
+        };
+
+        let (models, obj_materials) = tobj::load_obj(path, &load_options).map_err(|err| SceneError::Import {
+            path: path.to_path_buf(),
+            message: err.to_string()
+        })?;
+
+        // a missing or broken MTL shouldn't stop the geometry from loading
+        let obj_materials = obj_materials.unwrap_or_else(|err| {
+            log::warn!("{}: couldn't load materials, using a default: {err}", path.display());
+            Vec::new()
+        });
+
+        // only create the materials when they are going to be used
+        let material_indices: Vec<u32> = match options.material {
+            Some(_) => Vec::new(),
+            None => obj_materials.iter().map(|material| self.add_material(convert_material(material))).collect(),
+        };
+
+        let mut default_material = None;
+
+        for model in &models {
+            let mesh = &model.mesh;
+
+            let material = match (options.material, mesh.material_id) {
+                (Some(material), _) => material,
+                (None, Some(id)) if id < material_indices.len() => material_indices[id],
+                (None, _) => *default_material.get_or_insert_with(|| self.add_material(Material::diffuse(Vec3::splat(0.8)))),
+            };
+
+            let positions: Vec<Vec3> = mesh.positions.chunks_exact(3).map(|p| Vec3::new(p[0], p[1], p[2])).collect();
+
+            // zero length normals in the file are left as zero and replaced by the face normal below
+            let normals: Vec<Vec3> = if mesh.normals.len() == mesh.positions.len() {
+                mesh.normals.chunks_exact(3)
+                    .map(|n| Vec3::new(n[0], n[1], n[2]))
+                    .map(|normal| if normal.length() > 0.0 { normal.normalize() } else { Vec3::ZERO })
+                    .collect()
+            } else if options.normals == GeneratedNormals::Smooth {
+                smooth_normals(&positions, &mesh.indices)
+            } else {
+                Vec::new()
+            };
+
+            // OBJ puts the uv origin in the bottom left
+            let uvs: Vec<[f32; 2]> = if mesh.texcoords.len() / 2 == positions.len() {
+                mesh.texcoords.chunks_exact(2).map(|uv| [uv[0], 1.0 - uv[1]]).collect()
+            } else {
+                Vec::new()
+            };
+
+            for face in mesh.indices.chunks_exact(3) {
+                let face = [face[0] as usize, face[1] as usize, face[2] as usize];
+                let mut triangle = Triangle::new(positions[face[0]], positions[face[1]], positions[face[2]], material);
+
+                if !normals.is_empty() {
+                    let face_normal = triangle.normal();
+                    triangle.normals = face.map(|index| if normals[index] == Vec3::ZERO { face_normal } else { normals[index] });
+                }
+
+                if !uvs.is_empty() {
+                    triangle.uvs = face.map(|index| uvs[index]);
+                }
+
+                self.triangles.push(triangle);
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+
+fn convert_material(material: &tobj::Material) -> Material {
+    // Ke isn't part of the original MTL spec, so tobj leaves it in the unknown parameters
+    let emission = material.unknown_param.get("Ke")
+        .and_then(|value| {
+            let values: Vec<f32> = value.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+            return match values.as_slice() {
+                [r, g, b] => Some(Vec3::new(*r, *g, *b)),
+                [value] => Some(Vec3::splat(*value)),
+                _ => None,
+            };
+        })
+        .unwrap_or(Vec3::ZERO);
+
+    return Material {
+        albedo: material.diffuse.map_or(Vec3::splat(0.8), Vec3::from),
+        emission: emission,
+        ..Default::default()
+    };
+}
+
+
+/// Area weighted vertex normals: the un-normalised cross product of a face is twice its area
+fn smooth_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+
+    for face in indices.chunks_exact(3) {
+        let [a, b, c] = [face[0] as usize, face[1] as usize, face[2] as usize];
+        let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+
+        normals[a] += normal;
+        normals[b] += normal;
+        normals[c] += normal;
+    }
+
+    return normals.into_iter()
+        .map(|normal| if normal.length() > 0.0 { normal.normalize() } else { Vec3::Y })
+        .collect();
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn smooth_normals_are_area_weighted() {
+        // a large triangle facing +y and a small one facing +z, sharing the vertex at the origin
+        let positions = [
+            Vec3::ZERO, Vec3::new(0.0, 0.0, -2.0), Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+        ];
+        let normals = smooth_normals(&positions, &[0, 2, 1, 0, 3, 4]);
+
+        assert_eq!(normals[1], Vec3::Y);
+        assert_eq!(normals[3], Vec3::new(0.0, 0.0, 1.0));
+        assert!((normals[0] - Vec3::new(0.0, 4.0, 1.0).normalize()).length() < 1e-6);
+    }
+
+
+    #[test]
+    fn smooth_normals_of_degenerate_and_unused_vertices_are_finite() {
+        let positions = [Vec3::ZERO, Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::Y];
+        let normals = smooth_normals(&positions, &[0, 1, 2]);
+
+        assert!(normals.iter().all(|normal| (normal.length() - 1.0).abs() < 1e-6));
+    }
+
+
+    #[test]
+    fn emission_comes_from_ke() {
+        let with_ke = |value: &str| {
+            let mut material = tobj::Material::default();
+            material.unknown_param.insert("Ke".to_string(), value.to_string());
+            return convert_material(&material).emission;
+        };
+
+        assert_eq!(with_ke("1.0 2.0 3.0"), Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(with_ke("  4 "), Vec3::splat(4.0));
+        assert_eq!(with_ke("1.0 2.0"), Vec3::ZERO);
+        assert_eq!(with_ke("bright"), Vec3::ZERO);
+        assert_eq!(convert_material(&tobj::Material::default()).emission, Vec3::ZERO);
+    }
+
+
+    #[test]
+    fn zero_normals_in_the_file_fall_back_to_the_face_normal() {
+        let path = std::env::temp_dir().join(format!("obj-normals-test-{}.obj", std::process::id()));
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 0\nvn 0 0 2\nvn 0 0 1\nf 1//1 2//2 3//3\n").unwrap();
+
+        let mut scene = Scene::default();
+        let result = scene.load_obj(&path, &ObjOptions::default());
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(scene.triangles[0].normals, [Vec3::new(0.0, 0.0, 1.0); 3]);
+    }
+}
@@ -0,0 +1,411 @@
+//! Daylight from Preetham, Shirley and Smits' "A Practical Analytic Model for Daylight": the sky is baked into an
+//! environment map so the renderer importance samples it like any other, and the sun is a soft directional light
+//! dimmed and reddened by the air it shines through. North is -z, east +x and up +y.
+
+use std::f32::consts::PI;
+
+use crate::math::Vec3;
+
+use super::{EnvironmentMap, Light};
+
+
+
+
+/// Of the baked sky, the sun is a light of its own so this doesn't need to resolve it
+const MAP_WIDTH: u32 = 512;
+const MAP_HEIGHT: u32 = 256;
+
+/// The sun's angular diameter seen from the ground, in radians
+const SUN_ANGULAR_DIAMETER: f32 = 0.0093;
+
+/// Illuminance of the sun above the atmosphere, in kilolux like the model's kcd/m²
+const SUN_ILLUMINANCE: f32 = 128.0;
+
+/// kcd/m² per unit of radiance, which puts a white wall in the noon sun within a stop of 1 at exposure 0
+const UNIT: f32 = 20.0;
+
+
+
+
+/// Where the sun is in the sky
+#[derive(Clone, Copy, Debug)]
+pub enum SunPosition {
+    /// In radians, the elevation above the horizon and the azimuth from north towards east
+    Angles { elevation: f32, azimuth: f32 },
+    /// Worked out from where and when, with the NOAA's solar position equations
+    Location {
+        /// In degrees, north and east are positive
+        latitude: f32,
+        longitude: f32,
+        year: i32,
+        /// From 1, out of range months and days count as the nearest valid one
+        month: u32,
+        day: u32,
+        /// Local time in hours since midnight
+        time: f32,
+        /// Hours the local time is ahead of UTC
+        utc_offset: f32,
+    },
+}
+
+
+/// A clear sky with the sun in it
+#[derive(Clone, Copy, Debug)]
+pub struct Sky {
+    pub sun: SunPosition,
+    /// How hazy the air is, from 2 for a very clear day to about 10
+    pub turbidity: f32,
+    /// Multiplies the sky and the sun
+    pub intensity: f32,
+}
+
+
+impl Sky {
+    /// Elevation and azimuth of the sun in radians
+    pub fn sun_angles(&self) -> (f32, f32) {
+        return match self.sun {
+            SunPosition::Angles { elevation, azimuth } => (elevation, azimuth),
+            SunPosition::Location { latitude, longitude, year, month, day, time, utc_offset } => {
+                solar_position(latitude.to_radians(), longitude, day_of_year(year, month, day), time - utc_offset)
+            }
+        };
+    }
+
+
+    /// The way the sun shines, downwards while it's up
+    pub fn sun_direction(&self) -> Vec3 {
+        let (elevation, azimuth) = self.sun_angles();
+        return -towards(elevation, azimuth);
+    }
+
+
+    /// The sky without the sun. Nothing comes from below the horizon, scenes bring their own ground
+    pub fn environment_map(&self) -> EnvironmentMap {
+        // past the horizon the model breaks down, so the sky stays as it is at sunset
+        let (elevation, azimuth) = self.sun_angles();
+        let elevation = elevation.max(0.0);
+        let to_sun = towards(elevation, azimuth);
+
+        let model = Preetham::new(self.turbidity, PI / 2.0 - elevation);
+        let mut pixels = Vec::with_capacity((MAP_WIDTH * MAP_HEIGHT * 3) as usize);
+
+        for y in 0..MAP_HEIGHT {
+            let theta = PI * (y as f32 + 0.5) / MAP_HEIGHT as f32;
+
+            for x in 0..MAP_WIDTH {
+                // must match environment_direction in the shader
+                let phi = ((x as f32 + 0.5) / MAP_WIDTH as f32 - 0.5) * 2.0 * PI;
+                let direction = Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos());
+
+                let radiance = if direction.y > 0.0 {
+                    model.radiance(theta, direction.dot(to_sun).clamp(-1.0, 1.0).acos()) * (self.intensity / UNIT)
+                } else {
+                    Vec3::ZERO
+                };
+
+                pixels.extend([radiance.x, radiance.y, radiance.z]);
+            }
+        }
+
+        return EnvironmentMap {
+            width: MAP_WIDTH,
+            height: MAP_HEIGHT,
+            pixels: pixels,
+            rotation: 0.0,
+            intensity: 1.0
+        };
+    }
+
+
+    /// None once it has set
+    pub fn sun(&self) -> Option<Light> {
+        let (elevation, _) = self.sun_angles();
+        if elevation <= 0.0 {
+            return None;
+        }
+
+        let zenith_degrees = 90.0 - elevation.to_degrees();
+
+        // Kasten's relative air mass, how much more air the light crosses than it would straight down
+        let air_mass = 1.0 / (elevation.sin() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+
+        // Rayleigh scattering and Angstrom's aerosol turbidity at a red, a green and a blue wavelength in micrometres
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = [0.68f32, 0.55, 0.44].map(|lambda| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+            return rayleigh * aerosol;
+        });
+
+        return Some(Light::Directional {
+            direction: self.sun_direction(),
+            irradiance: Vec3::from(transmittance) * (SUN_ILLUMINANCE * self.intensity / UNIT),
+            angular_diameter: SUN_ANGULAR_DIAMETER
+        });
+    }
+}
+
+
+
+
+/// Up from the horizon by elevation, after turning from north towards east by azimuth
+fn towards(elevation: f32, azimuth: f32) -> Vec3 {
+    return Vec3::new(azimuth.sin() * elevation.cos(), elevation.sin(), -azimuth.cos() * elevation.cos());
+}
+
+
+
+
+/////////// Preetham
+
+/// The distribution of the sky's luminance and chromaticity for one turbidity and sun position
+struct Preetham {
+    /// Y in kcd/m², x and y at the zenith
+    zenith: Vec3,
+    /// The Perez function's A to E for Y, x and y
+    coefficients: [[f32; 5]; 3],
+    /// Of the sun from the zenith, in radians
+    theta_sun: f32,
+}
+
+
+impl Preetham {
+    fn new(turbidity: f32, theta_sun: f32) -> Self {
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let theta = [theta_sun * theta_sun * theta_sun, theta_sun * theta_sun, theta_sun, 1.0];
+        let chromaticity = |rows: [[f32; 4]; 3]| -> f32 {
+            let row = |coefficients: [f32; 4]| coefficients.iter().zip(theta).map(|(c, power)| c * power).sum::<f32>();
+            return t * t * row(rows[0]) + t * row(rows[1]) + row(rows[2]);
+        };
+
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        return Self {
+            zenith: Vec3::new(luminance, x, y),
+            coefficients: [
+                [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+                [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+                [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+            ],
+            theta_sun: theta_sun
+        };
+    }
+
+
+    /// In kcd/m² as linear sRGB, theta from the zenith and gamma from the sun
+    fn radiance(&self, theta: f32, gamma: f32) -> Vec3 {
+        let value = |index: usize| -> f32 {
+            let [a, b, c, d, e] = self.coefficients[index];
+
+            // the model's cos(theta) blows up at the horizon
+            let perez = |theta: f32, gamma: f32| (1.0 + a * (b / theta.cos().max(0.01)).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2));
+            return self.zenith[index] * perez(theta, gamma) / perez(0.0, self.theta_sun);
+        };
+
+        let (luminance, x, y) = (value(0), value(1), value(2));
+        if !(luminance > 0.0 && y > 0.0) {
+            return Vec3::ZERO;
+        }
+
+        let xyz = Vec3::new(x * luminance / y, luminance, (1.0 - x - y) * luminance / y);
+        let rgb = Vec3::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+        );
+
+        return rgb.max(Vec3::ZERO);
+    }
+}
+
+
+
+
+/////////// Solar position
+
+fn is_leap_year(year: i32) -> bool {
+    return (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+}
+
+
+/// Of a month from 1 to 12
+fn days_in_month(year: i32, month: u32) -> u32 {
+    return match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+}
+
+
+/// 1 for the first of January. Months outside 1 to 12 and days outside the month are clamped into them
+fn day_of_year(year: i32, month: u32, day: u32) -> u32 {
+    const DAYS_BEFORE: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+
+    let month = month.clamp(1, 12);
+    let day = day.clamp(1, days_in_month(year, month));
+    let leap_day = if is_leap_year(year) && month > 2 { 1 } else { 0 };
+
+    return DAYS_BEFORE[(month - 1) as usize] + day + leap_day;
+}
+
+
+/// Elevation and azimuth in radians, from the latitude in radians, longitude in degrees and hours since UTC midnight
+fn solar_position(latitude: f32, longitude: f32, day_of_year: u32, hour: f32) -> (f32, f32) {
+    // the fractional year in radians
+    let gamma = 2.0 * PI / 365.0 * (day_of_year as f32 - 1.0 + (hour - 12.0) / 24.0);
+
+    // in minutes
+    let equation_of_time = 229.18 * (0.000075 + 0.001868 * gamma.cos() - 0.032077 * gamma.sin()
+        - 0.014615 * (2.0 * gamma).cos() - 0.040849 * (2.0 * gamma).sin());
+
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin() - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin() - 0.002697 * (3.0 * gamma).cos() + 0.00148 * (3.0 * gamma).sin();
+
+    // true solar time in minutes, then the hour angle that is zero at solar noon
+    let solar_time = hour * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+    let cos_zenith = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    let elevation = PI / 2.0 - cos_zenith.clamp(-1.0, 1.0).acos();
+
+    // from south towards west, then turned to count from north towards east
+    let azimuth = hour_angle.sin().atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos()) + PI;
+
+    return (elevation, azimuth);
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Scene;
+
+
+    fn at(latitude: f32, longitude: f32, (year, month, day): (i32, u32, u32), time: f32, utc_offset: f32) -> Sky {
+        return Sky {
+            sun: SunPosition::Location {
+                latitude: latitude,
+                longitude: longitude,
+                year: year,
+                month: month,
+                day: day,
+                time: time,
+                utc_offset: utc_offset
+            },
+            turbidity: 3.0,
+            intensity: 1.0
+        };
+    }
+
+
+    #[test]
+    fn solar_position_matches_noaa() {
+        // elevation and azimuth in degrees from the NOAA solar calculator's spreadsheet equations, the first is also
+        // the example in NREL's solar position algorithm report (less its 0.02 degrees of refraction). The fractional
+        // year series solar_position uses instead is good to about a third of a degree
+        let cases = [
+            (at(39.742476, -105.1786, (2003, 10, 17), 12.508333, -7.0), 39.872, 194.343),
+            (at(48.14, 11.58, (2024, 6, 21), 15.5, 2.0), 53.728, 239.105),
+            (at(-33.87, 151.21, (2024, 12, 21), 12.0, 11.0), 74.365, 51.623),
+            (at(51.48, 0.0, (2024, 3, 20), 12.0, 0.0), 38.644, 177.666),
+            (at(0.0, 0.0, (2024, 3, 20), 6.0, 0.0), -1.841, 89.952),
+            (at(40.71, -74.01, (2024, 1, 15), 7.0, -5.0), -3.914, 114.741),
+            (at(64.15, -21.94, (2024, 12, 21), 12.0, 0.0), 0.813, 160.330),
+            (at(35.68, 139.69, (2023, 8, 1), 9.25, 9.0), 52.042, 107.318),
+        ];
+
+        for (sky, elevation, azimuth) in cases {
+            let (computed_elevation, computed_azimuth) = sky.sun_angles();
+            let (computed_elevation, computed_azimuth) = (computed_elevation.to_degrees(), computed_azimuth.to_degrees());
+
+            assert!((computed_elevation - elevation).abs() < 0.35, "{sky:?}: elevation {computed_elevation} instead of {elevation}");
+            assert!((computed_azimuth - azimuth).abs() < 0.35, "{sky:?}: azimuth {computed_azimuth} instead of {azimuth}");
+        }
+    }
+
+
+    #[test]
+    fn days_of_the_year() {
+        assert_eq!(day_of_year(2023, 1, 1), 1);
+        assert_eq!(day_of_year(2023, 3, 1), 60);
+        assert_eq!(day_of_year(2024, 3, 1), 61);
+        assert_eq!(day_of_year(2000, 12, 31), 366);
+        assert_eq!(day_of_year(1900, 12, 31), 365);
+
+        assert_eq!(day_of_year(2023, 2, 30), 59);
+        assert_eq!(day_of_year(2024, 2, 30), 60);
+        assert_eq!(day_of_year(2023, 4, 0), 91);
+        assert_eq!(day_of_year(2023, 13, 40), 365);
+    }
+
+
+    #[test]
+    fn directions_follow_the_compass() {
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-6;
+
+        assert!(close(towards(0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)));
+        assert!(close(towards(0.0, PI / 2.0), Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(towards(PI / 2.0, 1.0), Vec3::Y));
+
+        // a sun in the south east shines towards the north west
+        let sky = Sky { sun: SunPosition::Angles { elevation: 0.5, azimuth: 0.75 * PI }, turbidity: 3.0, intensity: 1.0 };
+        let direction = sky.sun_direction();
+        assert!(direction.x < 0.0 && direction.y < 0.0 && direction.z < 0.0);
+    }
+
+
+    #[test]
+    fn the_sun_sets() {
+        let sky = |elevation: f32| Sky { sun: SunPosition::Angles { elevation: elevation, azimuth: PI }, turbidity: 3.0, intensity: 1.0 };
+
+        assert!(sky(-0.1).sun().is_none());
+
+        let Some(Light::Directional { irradiance: noon, .. }) = sky(1.2).sun() else { panic!("the sun should be up") };
+        let Some(Light::Directional { irradiance: evening, .. }) = sky(0.05).sun() else { panic!("the sun should be up") };
+
+        // less and redder light through more air
+        assert!(evening.x < noon.x && evening.z < noon.z);
+        assert!(evening.z / evening.x < noon.z / noon.x);
+    }
+
+
+    #[test]
+    fn a_new_sky_replaces_the_old_sun() {
+        let sky = |elevation: f32| Sky { sun: SunPosition::Angles { elevation: elevation, azimuth: PI }, turbidity: 3.0, intensity: 1.0 };
+        let directional = |scene: &Scene| scene.all_lights().filter(|light| matches!(light, Light::Directional { .. })).count();
+
+        let mut scene = Scene::default();
+        scene.set_sky(&sky(0.5));
+        scene.lights.push(Light::Point { position: Vec3::Y, intensity: Vec3::ONE });
+        scene.set_sky(&sky(1.0));
+        assert_eq!(directional(&scene), 1);
+        assert_eq!(scene.all_lights().count(), 2);
+
+        // the sun stays out of lights, so changing them can't lose track of it
+        scene.lights.remove(0);
+        scene.set_sky(&sky(1.0));
+        assert_eq!(directional(&scene), 1);
+        assert!(scene.lights.is_empty());
+
+        // and after sunset there is none
+        scene.set_sky(&sky(-0.5));
+        assert_eq!(directional(&scene), 0);
+    }
+}
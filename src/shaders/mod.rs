@@ -1,36 +1,30 @@
-
-pub mod vertex_shader {
+// both variants compile the same source, RAY_QUERY swaps the BVH walk for hardware ray queries.
+// The structs and push constants must match the GPU structs in renderer/
+pub mod path_tracer {
     vulkano_shaders::shader!{
-        ty: "vertex",
-        src: r"
-            #version 460
-
-            layout(location = 0) in vec2 position;
-
-            void main() {
-                gl_Position = vec4(position, 0.0, 1.0);
-            }
-        ",
+        ty: "compute",
+        path: "src/shaders/path_tracer.comp",
     }
 }
 
 
-
-
-pub mod fragment_shader {
+pub mod path_tracer_ray_query {
     vulkano_shaders::shader!{
-        ty: "fragment",
-        src: r"
-            #version 460
+        ty: "compute",
+        path: "src/shaders/path_tracer.comp",
+        define: [("RAY_QUERY", "1")],
+        // ray queries need SPIR-V 1.4, which Vulkan 1.2 guarantees
+        vulkan_version: "1.2",
+        spirv_version: "1.4",
+    }
+}
 
-            // variable for the color of each pixel
-            // we can have any number of images to write to, so there is no builtin
-            layout(location = 0) out vec4 f_color;
 
-            void main() {
-                f_color = vec4(1.0, 0.0, 0.0, 1.0);
-            }
-        ",
+// turns the path tracer's accumulation into 8 bit sRGB, see renderer::DisplayTransform
+pub mod display {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        path: "src/shaders/display.comp",
     }
 }
 